@@ -0,0 +1,289 @@
+use std::{collections::HashSet, time::Duration};
+
+use serde::Serialize;
+
+use crate::{create_game, history::{split_teams, HistoricalGame, HistoricalTeam, StatsTracker, TeamFeatures}, prediction::{predict, win_probability, PredictionModel}, select_teams, selection::{self, lineup_score, team_imbalance}, stats::position_index_from_name, Algorithm, GameType, MVPCalculationMode, NamedOption, PlayerSlot, PlayerStats, SelectionRequest, SelectorError, StatsParameters};
+
+/// Calibration buckets for the favourite's predicted chance, 50-60% up to 90-100%
+const CALIBRATION_BUCKETS: usize = 5;
+
+/// Running totals for one rating method and smvp formula
+struct MethodResult {
+    mvp_calculation_mode: MVPCalculationMode,
+    formula: String,
+    games: u64,
+    correct: f64,
+    brier_total: f64,
+    bucket_games: [u64; CALIBRATION_BUCKETS],
+    bucket_predicted: [f64; CALIBRATION_BUCKETS],
+    bucket_won: [u64; CALIBRATION_BUCKETS]
+}
+
+/// How well one MVP calculation mode and smvp formula predicted history
+#[derive(Serialize)]
+pub struct MethodReport {
+    pub mvp_calculation_mode: String,
+    pub formula: String,
+    pub games: u64,
+    /// Share of games where the favourite won, a 50-50 call counts as half right
+    pub accuracy: f64,
+    /// Mean squared error of the predicted chance, lower is better
+    pub brier_score: f64,
+    pub calibration: Vec<CalibrationBucket>
+}
+
+/// Games where the favourite's predicted chance fell in one range, with how often the favourite won
+#[derive(Serialize)]
+pub struct CalibrationBucket {
+    pub favourite_chance_from: f64,
+    pub favourite_chance_to: f64,
+    pub games: u64,
+    pub mean_predicted: f64,
+    pub favourite_win_rate: f64
+}
+
+/// How balanced one selection algorithm's lineups for the players of every historical game would have been
+#[derive(Serialize)]
+pub struct AlgorithmReport {
+    /// The algorithm's name, or `as-played` for the teams that were actually played
+    pub algorithm: String,
+    pub games: u64,
+    /// Mean predicted chance of the favourite winning, closer to 0.5 is a closer game
+    pub favourite_chance: f64,
+    /// Mean difference in total smvp between the teams
+    pub team_imbalance: f64,
+    /// Mean lineup score, team imbalance plus slot mismatch
+    pub score: f64
+}
+
+impl AlgorithmReport {
+    fn new(algorithm : &str) -> AlgorithmReport {
+        AlgorithmReport { algorithm: algorithm.to_string(), games: 0, favourite_chance: 0.0, team_imbalance: 0.0, score: 0.0 }
+    }
+
+    /// Totals are kept while replaying, then turned into means with `finish`
+    fn add(&mut self, lineup : &[Vec<PlayerSlot>], model : &PredictionModel) {
+        let chances = predict(lineup, model, 1.0).map(|prediction| prediction.win_probability).unwrap_or_default();
+        self.games += 1;
+        self.favourite_chance += chances.iter().cloned().fold(0.0, f64::max);
+        self.team_imbalance += team_imbalance(lineup);
+        self.score += lineup_score(lineup);
+    }
+
+    fn finish(mut self) -> AlgorithmReport {
+        let games = self.games.max(1) as f64;
+        self.favourite_chance /= games;
+        self.team_imbalance /= games;
+        self.score /= games;
+        self
+    }
+}
+
+impl MethodResult {
+    fn add(&mut self, first_team_chance : f64, first_team_won : bool) {
+        let outcome = if first_team_won { 1.0 } else { 0.0 };
+
+        self.games += 1;
+        self.brier_total += (first_team_chance - outcome).powi(2);
+        if first_team_chance == 0.5 {
+            self.correct += 0.5;
+        } else if (first_team_chance > 0.5) == first_team_won {
+            self.correct += 1.0;
+        }
+
+        let (favourite_chance, favourite_won) = if first_team_chance >= 0.5 {
+            (first_team_chance, first_team_won)
+        } else {
+            (1.0 - first_team_chance, !first_team_won)
+        };
+        let bucket = (((favourite_chance - 0.5) * 10.0) as usize).min(CALIBRATION_BUCKETS - 1);
+        self.bucket_games[bucket] += 1;
+        self.bucket_predicted[bucket] += favourite_chance;
+        if favourite_won {
+            self.bucket_won[bucket] += 1;
+        }
+    }
+
+    fn report(&self) -> MethodReport {
+        let games = self.games.max(1) as f64;
+        let calibration = (0..CALIBRATION_BUCKETS).map(|bucket| {
+            let bucket_games = self.bucket_games[bucket].max(1) as f64;
+            CalibrationBucket {
+                favourite_chance_from: 0.5 + bucket as f64 / 10.0,
+                favourite_chance_to: 0.6 + bucket as f64 / 10.0,
+                games: self.bucket_games[bucket],
+                mean_predicted: self.bucket_predicted[bucket] / bucket_games,
+                favourite_win_rate: self.bucket_won[bucket] as f64 / bucket_games
+            }
+        }).collect();
+
+        MethodReport {
+            mvp_calculation_mode: self.mvp_calculation_mode.name().to_string(),
+            formula: self.formula.clone(),
+            games: self.games,
+            accuracy: self.correct / games,
+            brier_score: self.brier_total / games,
+            calibration
+        }
+    }
+}
+
+/// Replay history and score how well each MVP calculation mode and smvp formula would have predicted every game.
+/// A game is predicted before it is recorded, so it never counts towards its own prediction.
+/// Every mode uses the other settings in `stats`. Returns a report for every mode and formula, modes in the order they are listed
+pub fn run_backtest(history : &[HistoricalGame], formulas : &[(String, PredictionModel)], stats : &StatsParameters) -> Vec<MethodReport> {
+
+    let modes: Vec<StatsParameters> = MVPCalculationMode::ALL.iter().map(|mode| StatsParameters { mvp_calc_mode: mode.clone(), ..stats.clone() }).collect();
+
+    let mut results: Vec<MethodResult> = Vec::new();
+    for mode in &modes {
+        for (formula, _) in formulas {
+            results.push(MethodResult {
+                mvp_calculation_mode: mode.mvp_calc_mode.clone(),
+                formula: formula.clone(),
+                games: 0,
+                correct: 0.0,
+                brier_total: 0.0,
+                bucket_games: [0; CALIBRATION_BUCKETS],
+                bucket_predicted: [0.0; CALIBRATION_BUCKETS],
+                bucket_won: [0; CALIBRATION_BUCKETS]
+            });
+        }
+    }
+
+    let mut tracker = StatsTracker::default();
+
+    for game in history {
+        // Draws and games with missing results can't be scored
+        if let Some([first, second]) = split_teams(game) && first.won != second.won {
+            for (mode_index, mode) in modes.iter().enumerate() {
+                let first_features = tracker.team_features(&first, mode);
+                let second_features = tracker.team_features(&second, mode);
+
+                for (formula_index, (_, model)) in formulas.iter().enumerate() {
+                    let strengths = [team_strength(&first_features, model), team_strength(&second_features, model)];
+                    let chances = win_probability(&strengths, model);
+                    results[mode_index * formulas.len() + formula_index].add(chances[0], first.won);
+                }
+            }
+        }
+        tracker.record(game);
+    }
+
+    return results.iter().map(|result| result.report()).collect();
+}
+
+/// Total team smvp, the same number the selector would add up for this team
+fn team_strength(features : &TeamFeatures, model : &PredictionModel) -> f64 {
+    let positions: f64 = features[..5].iter().zip(&model.position_weights).map(|(feature, weight)| feature * weight).sum();
+    positions + features[5] * model.games_weight
+}
+
+/// Replay history, making teams out of every game's players with each algorithm, and score how balanced they would have been.
+/// Players' stats only use games before the one being replayed, and the teams actually played are scored the same way as
+/// `as-played`, first. Only two team games laid out like one of the game types are replayed, each seeded with its game id
+pub fn run_algorithm_backtest(history : &[HistoricalGame], algorithms : &[Algorithm], stats : &StatsParameters, model : &PredictionModel, time_budget : Duration) -> Result<Vec<AlgorithmReport>, SelectorError> {
+
+    let mut reports = vec![AlgorithmReport::new("as-played")];
+    reports.extend(algorithms.iter().map(|algorithm| AlgorithmReport::new(algorithm.name())));
+
+    let mut tracker = StatsTracker::default();
+
+    for game in history {
+        let player_ids: HashSet<u64> = game.scorecards.iter().map(|card| card.player_id).collect();
+        // A player listed twice can't be selected twice
+        if let Some(teams) = split_teams(game) && player_ids.len() == game.scorecards.len() && let Some(game_type) = matching_game_type(&teams) {
+            let players: Vec<PlayerStats> = game.scorecards.iter().map(|card| tracker.stats_before(card.player_id, stats)).collect();
+            reports[0].add(&as_played(&teams, &game_type, &players, model), model);
+
+            for (index, algorithm) in algorithms.iter().enumerate() {
+                let selection = select_teams(SelectionRequest {
+                    game_type: game_type.clone(),
+                    team_count: 2,
+                    algorithm: algorithm.clone(),
+                    players: players.clone(),
+                    modifier_position: None,
+                    modifier_team: None,
+                    model: model.clone(),
+                    seed: game.game_id as u64,
+                    candidates: 1,
+                    time_budget,
+                    refine_iterations: None
+                })?;
+                reports[index + 1].add(&selection.lineups[0].teams, model);
+            }
+        }
+        tracker.record(game);
+    }
+
+    return Ok(reports.into_iter().map(AlgorithmReport::finish).collect());
+}
+
+/// The game type whose teams have exactly the positions both of these teams played
+fn matching_game_type(teams : &[HistoricalTeam; 2]) -> Option<GameType> {
+    let positions = |team : &HistoricalTeam| {
+        let mut positions: Option<Vec<u64>> = team.scorecards.iter().map(|card| position_index_from_name(&card.position)).collect();
+        if let Some(positions) = &mut positions {
+            positions.sort();
+        }
+        positions
+    };
+    let played = positions(&teams[0])?;
+    if positions(&teams[1]).as_ref() != Some(&played) {
+        return None;
+    }
+
+    return GameType::ALL.iter().find(|game_type| {
+        let mut slots: Vec<u64> = create_game((*game_type).clone(), 1)[0].iter().map(|slot| slot.position).collect();
+        slots.sort();
+        slots == played
+    }).cloned();
+}
+
+/// The teams as they were played, with the players' stats going into the game
+fn as_played(teams : &[HistoricalTeam; 2], game_type : &GameType, players : &[PlayerStats], model : &PredictionModel) -> Vec<Vec<PlayerSlot>> {
+    let mut lineup = create_game(game_type.clone(), 2);
+    for (team_index, team) in teams.iter().enumerate() {
+        for card in &team.scorecards {
+            // matching_game_type checked every position has a slot
+            let position = position_index_from_name(&card.position).unwrap();
+            let slot = lineup[team_index].iter_mut().find(|slot| slot.position == position && slot.player_id == -1).unwrap();
+            let player = players.iter().find(|player| player.player_id == card.player_id).unwrap().clone();
+            selection::fill_slot(player, slot, model);
+        }
+    }
+    return lineup;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{stats::set_position_from_index, test_support::scorecard};
+
+    /// Players 1 to 5 always play red and players 6 to 10 green, one at each position. Everyone scores their own id in MVP,
+    /// so green is always much stronger
+    fn lopsided_history(games : i64) -> Vec<HistoricalGame> {
+        return (0..games).map(|game_id| {
+            let scorecards = (1..=10u64).map(|player_id| {
+                let team = if player_id <= 5 { "red" } else { "green" };
+                scorecard(player_id, &set_position_from_index((player_id - 1) % 5), player_id as f64, game_id, team, player_id > 5)
+            }).collect();
+            HistoricalGame { game_id, scorecards }
+        }).collect();
+    }
+
+    #[test]
+    fn algorithms_are_scored_against_the_teams_played() {
+        let mut history = lopsided_history(20);
+        // Only one team, so it isn't replayed
+        history.push(HistoricalGame { game_id: 20, scorecards: history[0].scorecards.iter().filter(|card| card.team == "red").cloned().collect() });
+
+        let stats = StatsParameters::new(MVPCalculationMode::Mean, 50, Default::default(), 10.0, 365.0).unwrap();
+        let reports = run_algorithm_backtest(&history, &[Algorithm::Simple_Selection], &stats, &PredictionModel::default(), Duration::from_millis(100)).unwrap();
+
+        assert_eq!(reports.iter().map(|report| report.algorithm.as_str()).collect::<Vec<_>>(), vec!["as-played", "simple-selection"]);
+        assert!(reports.iter().all(|report| report.games == 20));
+        assert!(reports[1].team_imbalance < reports[0].team_imbalance);
+        assert!(reports[1].favourite_chance < reports[0].favourite_chance);
+    }
+}
@@ -0,0 +1,405 @@
+use std::{path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{error::SelectorError, source::DataSource, PopulationStats, Scorecard};
+
+const CACHE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS scorecards (
+        player_id INTEGER NOT NULL,
+        game_id INTEGER NOT NULL,
+        mvp_points REAL NOT NULL,
+        hit_diff REAL NOT NULL,
+        position TEXT NOT NULL,
+        player_name TEXT NOT NULL,
+        team TEXT NOT NULL,
+        won INTEGER NOT NULL,
+        played_at INTEGER NOT NULL,
+        PRIMARY KEY (player_id, game_id)
+    );
+    CREATE TABLE IF NOT EXISTS players (
+        player_id INTEGER PRIMARY KEY,
+        synced_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS population (
+        position INTEGER PRIMARY KEY,
+        mvp REAL NOT NULL,
+        hit_diff REAL NOT NULL,
+        synced_at INTEGER NOT NULL
+    );
+";
+
+/// Fetching new games starts this long before the newest cached game, in case Postgres and the cache disagree on time zones.
+/// Games fetched twice replace the cached copy
+const SYNC_OVERLAP_SECONDS: i64 = 86400;
+
+/// Keeps players' scorecards from another data source in a local SQLite database. Players synced within max_age are read
+/// straight from the cache, older ones only fetch games since their newest cached game. When the source can't be reached,
+/// whatever is cached is used instead, however old
+pub struct CachedSource {
+    inner: Box<dyn DataSource>,
+    connection: Connection,
+    max_age: i64,
+    inner_available: Option<bool>
+}
+
+impl CachedSource {
+    pub fn open(inner : Box<dyn DataSource>, path : &Path, max_age : u64) -> Result<CachedSource, SelectorError> {
+        let connection = Connection::open(path).map_err(|error| SelectorError::Config(format!("Could not open cache {}: {}", path.display(), error)))?;
+        connection.execute_batch(CACHE_SCHEMA).map_err(|error| SelectorError::Config(format!("Could not set up cache {}: {}", path.display(), error)))?;
+
+        return Ok(CachedSource { inner, connection, max_age: max_age as i64, inner_available: None });
+    }
+
+    /// Only try reaching the source once, a connection timeout for every lookup would make the cache pointless
+    fn inner_available(&mut self) -> Result<bool, SelectorError> {
+        if self.inner_available.is_none() {
+            self.inner_available = Some(self.inner.is_available()?);
+        }
+        return Ok(self.inner_available.unwrap());
+    }
+
+    fn synced_at(&self, player_id : u64) -> Result<Option<i64>, SelectorError> {
+        return self.connection.query_row("SELECT synced_at FROM players WHERE player_id = ?1", params![player_id as i64], |row| row.get(0))
+            .optional()
+            .map_err(cache_error);
+    }
+
+    fn newest_cached_game(&self, player_id : u64) -> Result<Option<i64>, SelectorError> {
+        return self.connection.query_row("SELECT MAX(played_at) FROM scorecards WHERE player_id = ?1", params![player_id as i64], |row| row.get(0))
+            .map_err(cache_error);
+    }
+
+    /// Fetch new games for players who have been synced before, and everything for players who haven't
+    fn sync_players(&mut self, player_ids : &[u64], now : i64) -> Result<(), SelectorError> {
+        let mut synced = Vec::new();
+        let mut unsynced = Vec::new();
+        for player_id in player_ids {
+            match self.synced_at(*player_id)? {
+                Some(_) => synced.push(*player_id),
+                None => unsynced.push(*player_id)
+            }
+        }
+
+        let mut scorecards = Vec::new();
+        if !unsynced.is_empty() {
+            scorecards.extend(self.inner.player_scorecards(&unsynced)?);
+        }
+        if !synced.is_empty() {
+            // Players without any games yet have nothing to start from, so they fetch everything
+            let mut since = i64::MAX;
+            for player_id in &synced {
+                since = since.min(self.newest_cached_game(*player_id)?.unwrap_or(0));
+            }
+            scorecards.extend(self.inner.player_scorecards_since(&synced, since - SYNC_OVERLAP_SECONDS)?);
+        }
+
+        let transaction = self.connection.transaction().map_err(cache_error)?;
+        for card in &scorecards {
+            transaction.execute(
+                "INSERT OR REPLACE INTO scorecards (player_id, game_id, mvp_points, hit_diff, position, player_name, team, won, played_at) \
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![card.player_id as i64, card.game_id, card.mvp_points, card.hit_diff, card.position, card.player_name, card.team, card.won, card.played_at]
+            ).map_err(cache_error)?;
+        }
+        for player_id in player_ids {
+            transaction.execute("INSERT OR REPLACE INTO players (player_id, synced_at) VALUES (?1, ?2)", params![*player_id as i64, now])
+                .map_err(cache_error)?;
+        }
+        transaction.commit().map_err(cache_error)?;
+        return Ok(());
+    }
+
+    fn cached_scorecards(&self, player_id : u64) -> Result<Vec<Scorecard>, SelectorError> {
+        let mut statement = self.connection.prepare(
+            "SELECT mvp_points, hit_diff, position, player_name, player_id, game_id, team, won, played_at FROM scorecards \
+            WHERE player_id = ?1 ORDER BY played_at DESC, game_id DESC"
+        ).map_err(cache_error)?;
+
+        let scorecards = statement.query_map(params![player_id as i64], |row| {
+            let player_id: i64 = row.get(4)?;
+            Ok(Scorecard {
+                mvp_points: row.get(0)?,
+                hit_diff: row.get(1)?,
+                position: row.get(2)?,
+                player_name: row.get(3)?,
+                player_id: player_id as u64,
+                game_id: row.get(5)?,
+                team: row.get(6)?,
+                won: row.get(7)?,
+                played_at: row.get(8)?
+            })
+        }).map_err(cache_error)?;
+
+        return scorecards.collect::<Result<Vec<Scorecard>, _>>().map_err(cache_error);
+    }
+
+    /// The cached population stats and when they were synced, if they ever were
+    fn cached_population_stats(&self) -> Result<Option<(PopulationStats, i64)>, SelectorError> {
+        let mut statement = self.connection.prepare("SELECT position, mvp, hit_diff, synced_at FROM population").map_err(cache_error)?;
+        let rows = statement.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?, row.get::<_, f64>(2)?, row.get::<_, i64>(3)?)))
+            .map_err(cache_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(cache_error)?;
+
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let mut population = PopulationStats { mvp: [7.0; 5], hit_diff: [1.0; 5] };
+        let mut synced_at = i64::MAX;
+        for (position, mvp, hit_diff, row_synced_at) in rows {
+            population.mvp[position as usize] = mvp;
+            population.hit_diff[position as usize] = hit_diff;
+            synced_at = synced_at.min(row_synced_at);
+        }
+        return Ok(Some((population, synced_at)));
+    }
+}
+
+impl DataSource for CachedSource {
+    fn player_scorecards(&mut self, player_ids : &[u64]) -> Result<Vec<Scorecard>, SelectorError> {
+        let now = now();
+
+        let mut stale = Vec::new();
+        let mut never_synced = Vec::new();
+        for player_id in player_ids {
+            match self.synced_at(*player_id)? {
+                Some(synced_at) if now - synced_at < self.max_age => {}
+                Some(_) => stale.push(*player_id),
+                None => {
+                    stale.push(*player_id);
+                    never_synced.push(*player_id);
+                }
+            }
+        }
+        stale.sort();
+        stale.dedup();
+
+        if !stale.is_empty() {
+            if self.inner_available()? {
+                self.sync_players(&stale, now)?;
+            } else {
+                if !never_synced.is_empty() {
+                    return Err(SelectorError::Connection(format!("Players {:?} have never been cached and the data source can't be reached.", never_synced)));
+                }
+                eprintln!("Warning: the data source can't be reached, using old cached scorecards for players {:?}", stale);
+            }
+        }
+
+        let mut scorecards: Vec<Scorecard> = Vec::new();
+        for player_id in player_ids {
+            scorecards.extend(self.cached_scorecards(*player_id)?);
+        }
+        scorecards.sort_by(|a, b| b.played_at.cmp(&a.played_at).then(b.game_id.cmp(&a.game_id)));
+        return Ok(scorecards);
+    }
+
+    /// The full history is only used by the glicko mode, train and backtest, so it is never cached
+    fn history(&mut self) -> Result<Vec<Scorecard>, SelectorError> {
+        return self.inner.history();
+    }
+
+    fn population_stats(&mut self) -> Result<PopulationStats, SelectorError> {
+        let now = now();
+        let cached = self.cached_population_stats()?;
+
+        if let Some((population, synced_at)) = cached && now - synced_at < self.max_age {
+            return Ok(population);
+        }
+
+        if !self.inner_available()? {
+            let Some((population, _)) = cached else {
+                return Err(SelectorError::Connection(String::from("Population stats have never been cached and the data source can't be reached.")));
+            };
+            eprintln!("Warning: the data source can't be reached, using old cached population stats.");
+            return Ok(population);
+        }
+
+        let population = self.inner.population_stats()?;
+        let transaction = self.connection.transaction().map_err(cache_error)?;
+        for position in 0..5 {
+            transaction.execute(
+                "INSERT OR REPLACE INTO population (position, mvp, hit_diff, synced_at) VALUES (?1, ?2, ?3, ?4)",
+                params![position as i64, population.mvp[position], population.hit_diff[position], now]
+            ).map_err(cache_error)?;
+        }
+        transaction.commit().map_err(cache_error)?;
+
+        return Ok(population);
+    }
+
+    /// Names are looked up in the source when it can be reached, so players new to the cache are found. Otherwise only
+    /// cached players can be found
+    fn player_ids_by_name(&mut self, player_name : &str) -> Result<Vec<u64>, SelectorError> {
+        if self.inner_available()? {
+            return self.inner.player_ids_by_name(player_name);
+        }
+
+        let mut statement = self.connection.prepare("SELECT DISTINCT player_id FROM scorecards WHERE LOWER(player_name) = LOWER(?1) ORDER BY player_id")
+            .map_err(cache_error)?;
+        return statement.query_map(params![player_name], |row| row.get::<_, i64>(0))
+            .map_err(cache_error)?
+            .map(|player_id| player_id.map(|player_id| player_id as u64).map_err(cache_error))
+            .collect();
+    }
+
+    /// Asks the source again every time, so a health check sees a source that went down or came back
+    fn is_available(&mut self) -> Result<bool, SelectorError> {
+        let available = self.inner.is_available()?;
+        self.inner_available = Some(available);
+        return Ok(available);
+    }
+}
+
+fn cache_error(error : rusqlite::Error) -> SelectorError {
+    return SelectorError::Query(format!("Failed to use the cache: {}", error));
+}
+
+fn now() -> i64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).expect("The clock is set before 1970").as_secs() as i64;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{source::FileSource, test_support::{scorecard, temp_file, temp_path}};
+
+    const DAY: i64 = 86400;
+
+    /// A source that can never be reached, like a Postgres server that is down
+    struct Offline;
+
+    impl DataSource for Offline {
+        fn player_scorecards(&mut self, _player_ids : &[u64]) -> Result<Vec<Scorecard>, SelectorError> {
+            panic!("The cache asked an unreachable source for scorecards");
+        }
+
+        fn history(&mut self) -> Result<Vec<Scorecard>, SelectorError> {
+            panic!("The cache asked an unreachable source for the history");
+        }
+
+        fn population_stats(&mut self) -> Result<PopulationStats, SelectorError> {
+            panic!("The cache asked an unreachable source for population stats");
+        }
+
+        fn player_ids_by_name(&mut self, _player_name : &str) -> Result<Vec<u64>, SelectorError> {
+            panic!("The cache asked an unreachable source for player ids");
+        }
+
+        fn is_available(&mut self) -> Result<bool, SelectorError> {
+            return Ok(false);
+        }
+    }
+
+    /// A file source holding the given scorecards
+    fn file_source(name : &str, scorecards : &[Scorecard]) -> Box<dyn DataSource> {
+        let path = temp_file(name, &serde_json::to_string(scorecards).unwrap());
+        let source = FileSource::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        return Box::new(source);
+    }
+
+    /// Player 1's game, played the given number of days after the epoch
+    fn game(game_id : i64, days : i64, mvp_points : f64) -> Scorecard {
+        return Scorecard { played_at: days * DAY, ..scorecard(1, "Commander", mvp_points, game_id, "red", true) };
+    }
+
+    fn mvp_by_game(scorecards : &[Scorecard]) -> Vec<(i64, f64)> {
+        return scorecards.iter().map(|card| (card.game_id, card.mvp_points)).collect();
+    }
+
+    #[test]
+    fn stale_players_refetch_from_a_day_before_their_newest_game() {
+        let path = temp_path("overlap.sqlite");
+        let mut cache = CachedSource::open(file_source("overlap-1.json", &[game(1, 10, 5.0), game(2, 20, 5.0)]), &path, 0).unwrap();
+        assert_eq!(mvp_by_game(&cache.player_scorecards(&[1]).unwrap()), vec![(2, 5.0), (1, 5.0)]);
+        drop(cache);
+
+        // Game 2 was corrected and game 3 was late to arrive but played within the overlap. Game 1 changed too, but was
+        // played long before the overlap, so the cached copy is kept
+        let source = file_source("overlap-2.json", &[game(1, 10, 9.0), game(2, 20, 9.0), game(3, 20 - 1, 9.0), game(4, 21, 9.0)]);
+        let mut cache = CachedSource::open(source, &path, 0).unwrap();
+        let scorecards = cache.player_scorecards(&[1]).unwrap();
+        drop(cache);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mvp_by_game(&scorecards), vec![(4, 9.0), (2, 9.0), (3, 9.0), (1, 5.0)]);
+    }
+
+    #[test]
+    fn fresh_players_are_read_from_the_cache() {
+        let path = temp_path("fresh.sqlite");
+        let mut cache = CachedSource::open(file_source("fresh-1.json", &[game(1, 10, 5.0)]), &path, 3600).unwrap();
+        cache.player_scorecards(&[1]).unwrap();
+        drop(cache);
+
+        let mut cache = CachedSource::open(file_source("fresh-2.json", &[game(1, 10, 9.0), game(2, 11, 9.0)]), &path, 3600).unwrap();
+        let scorecards = cache.player_scorecards(&[1]).unwrap();
+        drop(cache);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mvp_by_game(&scorecards), vec![(1, 5.0)]);
+    }
+
+    #[test]
+    fn old_cached_scorecards_are_used_when_the_source_is_unreachable() {
+        let path = temp_path("offline.sqlite");
+        let mut cache = CachedSource::open(file_source("offline.json", &[game(1, 10, 5.0), game(2, 20, 6.0)]), &path, 0).unwrap();
+        cache.player_scorecards(&[1]).unwrap();
+        drop(cache);
+
+        let mut cache = CachedSource::open(Box::new(Offline), &path, 0).unwrap();
+        let scorecards = cache.player_scorecards(&[1]).unwrap();
+        drop(cache);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mvp_by_game(&scorecards), vec![(2, 6.0), (1, 5.0)]);
+    }
+
+    #[test]
+    fn players_never_cached_are_an_error_when_the_source_is_unreachable() {
+        let path = temp_path("never-cached.sqlite");
+        let mut cache = CachedSource::open(file_source("never-cached.json", &[game(1, 10, 5.0)]), &path, 0).unwrap();
+        cache.player_scorecards(&[1]).unwrap();
+        drop(cache);
+
+        let mut cache = CachedSource::open(Box::new(Offline), &path, 0).unwrap();
+        let result = cache.player_scorecards(&[1, 2]);
+        drop(cache);
+        fs::remove_file(&path).unwrap();
+
+        let Err(SelectorError::Connection(message)) = result else { panic!("Expected a connection error") };
+        assert!(message.contains("[2]"), "{}", message);
+    }
+
+    #[test]
+    fn population_stats_are_cached() {
+        let path = temp_path("population.sqlite");
+        let commander = |mvp_points : f64| vec![scorecard(1, "Commander", mvp_points, 1, "red", true)];
+
+        let mut cache = CachedSource::open(Box::new(Offline), &path, 3600).unwrap();
+        assert!(matches!(cache.population_stats(), Err(SelectorError::Connection(_))));
+        drop(cache);
+
+        let mut cache = CachedSource::open(file_source("population-1.json", &commander(5.0)), &path, 3600).unwrap();
+        assert_eq!(cache.population_stats().unwrap().mvp[0], 5.0);
+        drop(cache);
+
+        // Still fresh, so the source's new stats aren't read
+        let mut cache = CachedSource::open(file_source("population-2.json", &commander(9.0)), &path, 3600).unwrap();
+        assert_eq!(cache.population_stats().unwrap().mvp[0], 5.0);
+        drop(cache);
+
+        let mut cache = CachedSource::open(Box::new(Offline), &path, 0).unwrap();
+        assert_eq!(cache.population_stats().unwrap().mvp[0], 5.0);
+        drop(cache);
+
+        let mut cache = CachedSource::open(file_source("population-3.json", &commander(9.0)), &path, 0).unwrap();
+        assert_eq!(cache.population_stats().unwrap().mvp[0], 9.0);
+        drop(cache);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,46 +1,189 @@
-use std::env;
+use std::{env, fs, time::Duration};
 
 use dotenvy::dotenv;
-use postgres::Client;
+use postgres::{config::SslMode as PostgresSslMode, types::ToSql, Client, Config};
 use postgres_native_tls::MakeTlsConnector;
-use native_tls::TlsConnector;
+use native_tls::{Certificate, TlsConnector};
 
-use crate::DatabaseParameters;
+use crate::{error::SelectorError, stats::position_index_from_name, DatabaseOptions, DatabaseParameters, NamedOption, PopulationStats, Scorecard, SslMode};
 
 
-pub fn create_db_client(params : DatabaseParameters) -> postgres::Client {
+pub fn create_db_client(params : DatabaseParameters) -> Result<postgres::Client, SelectorError> {
 
-    let connector = TlsConnector::builder()
-        .danger_accept_invalid_certs(true)
-        .build().unwrap();
+    let mut connector = TlsConnector::builder();
+
+    if let Some(path) = &params.ca_cert {
+        let pem = fs::read(path).map_err(|error| SelectorError::Config(format!("Could not read CA certificate {}: {}", path.display(), error)))?;
+        let certificate = Certificate::from_pem(&pem).map_err(|error| SelectorError::Config(format!("CA certificate {} is not a valid PEM certificate: {}", path.display(), error)))?;
+        connector.add_root_certificate(certificate);
+    }
+
+    // verify-ca trusts any hostname on a certificate signed by a trusted CA, every other mode checks both
+    if matches!(params.ssl_mode, SslMode::Verify_Ca) {
+        connector.danger_accept_invalid_hostnames(true);
+    }
+
+    if params.accept_invalid_certs {
+        eprintln!("Warning: accepting any certificate from the database, the connection can be intercepted.");
+        connector.danger_accept_invalid_certs(true);
+        connector.danger_accept_invalid_hostnames(true);
+    }
+
+    let connector = connector.build().map_err(|error| SelectorError::Config(format!("Unable to set up TLS for the database connection: {}", error)))?;
     let tls = MakeTlsConnector::new(connector);
 
-    let db_params = format!("host={} port=5432 dbname={} user={} password={} sslmode=require", params.host, params.dbname, params.user, params.password);
+    let mut config = Config::new();
+    config.host(&params.host)
+        .port(params.port)
+        .dbname(&params.dbname)
+        .user(&params.user)
+        .password(&params.password)
+        .ssl_mode(match params.ssl_mode {
+            SslMode::Disable => PostgresSslMode::Disable,
+            SslMode::Prefer => PostgresSslMode::Prefer,
+            SslMode::Require | SslMode::Verify_Ca | SslMode::Verify_Full => PostgresSslMode::Require
+        });
 
-    let client = Client::connect(
-        &db_params, 
-        tls
-    );
+    if let Some(timeout) = params.connect_timeout {
+        config.connect_timeout(timeout);
+    }
 
-    return client.expect("Unable to connect to lfstats database");
+    return config.connect(tls).map_err(|error| SelectorError::Connection(format!("Unable to connect to lfstats database: {}", error)));
 }
 
 
 
-pub fn set_database_params() -> DatabaseParameters {
+/// Read the connection settings from the environment (and .env), with any given in the options taking priority
+pub fn set_database_params(database : &DatabaseOptions) -> Result<DatabaseParameters, SelectorError> {
+
+    // The settings can all come from the real environment, so only a .env file that exists but can't be read is a problem
+    if let Err(error) = dotenv() && !error.not_found() {
+        return Err(SelectorError::Config(format!("Failed to load .env file: {}", error)));
+    }
 
-    dotenv().expect("Failed to load .env file");
-    
-    let host = env::var("DATABASE_HOST")
-        .expect("Could not load DATABASE_HOST from environment. Contact Metalface if you're seeing this and you're not him.");
-    let dbname = env::var("DATABASE_NAME")
-        .expect("Could not load DATABASE_NAME from environment. Contact Metalface if you're seeing this and you're not him.");
-    let user = env::var("DATABASE_USERNAME")
-        .expect("Could not load DATABASE_USERNAME from environment. Contact Metalface if you're seeing this and you're not him.");
-    let password = env::var("DATABASE_PASSWORD")
-        .expect("Could not load DATABASE_PASSWORD from environment. Contact Metalface if you're seeing this and you're not him.");
+    let host = required_env("DATABASE_HOST")?;
+    let dbname = required_env("DATABASE_NAME")?;
+    let user = required_env("DATABASE_USERNAME")?;
+    let password = required_env("DATABASE_PASSWORD")?;
 
+    let port = match (database.port, env::var("DATABASE_PORT")) {
+        (Some(port), _) => port,
+        (None, Ok(port)) => port.parse().map_err(|_| SelectorError::Config(format!("DATABASE_PORT must be a port number, got {}", port)))?,
+        (None, Err(_)) => 5432
+    };
 
-    let params = DatabaseParameters { host, dbname, user, password };
-    return params;
-}
\ No newline at end of file
+    let ssl_mode = match (database.ssl_mode, env::var("DATABASE_SSLMODE")) {
+        (Some(mode), _) => mode,
+        (None, Ok(mode)) => SslMode::from_name(&mode)
+            .ok_or_else(|| SelectorError::Config(format!("DATABASE_SSLMODE must be disable, prefer, require, verify-ca or verify-full, got {}", mode)))?,
+        (None, Err(_)) => SslMode::default()
+    };
+
+    let ca_cert = database.ca_cert.clone().or_else(|| env::var("DATABASE_CA_CERT").ok().map(Into::into));
+
+    let connect_timeout = match (database.connect_timeout, env::var("DATABASE_CONNECT_TIMEOUT")) {
+        (Some(seconds), _) => Some(seconds),
+        (None, Ok(seconds)) => Some(seconds.parse().map_err(|_| SelectorError::Config(format!("DATABASE_CONNECT_TIMEOUT must be a number of seconds, got {}", seconds)))?),
+        (None, Err(_)) => None
+    }.map(Duration::from_secs);
+
+    // Only an explicit yes turns certificate checks off
+    let accept_invalid_certs = database.accept_invalid_certs || env::var("DATABASE_ACCEPT_INVALID_CERTS")
+        .is_ok_and(|value| matches!(value.to_lowercase().as_str(), "1" | "true" | "yes"));
+
+    let params = DatabaseParameters { host, port, dbname, user, password, ssl_mode, ca_cert, connect_timeout, accept_invalid_certs };
+    return Ok(params);
+}
+
+fn required_env(name : &str) -> Result<String, SelectorError> {
+    return env::var(name).map_err(|_| SelectorError::Config(format!("Could not load {} from environment. Contact Metalface if you're seeing this and you're not him.", name)));
+}
+
+
+// Every query below is a prepared statement with bound parameters, never built by formatting values into the SQL
+
+/// Every scorecard query selects the same columns, in the order scorecard_from_row reads them
+const SCORECARD_SELECT: &str = "SELECT s.mvp_points, s.hit_diff, s.position, s.player_name, s.player_id::bigint, s.game_id::bigint, s.team, COALESCE(g.winner = s.team, false), \
+    EXTRACT(EPOCH FROM s.game_datetime)::bigint \
+    FROM scorecards s LEFT JOIN games g ON g.id = s.game_id";
+
+fn scorecard_from_row(row : &postgres::Row) -> Result<Scorecard, postgres::Error> {
+    let player_id: i64 = row.try_get(4)?;
+    Ok(Scorecard {
+        mvp_points: row.try_get(0)?,
+        hit_diff: row.try_get(1)?,
+        position: row.try_get(2)?,
+        player_name: row.try_get(3)?,
+        player_id: player_id as u64,
+        game_id: row.try_get(5)?,
+        team: row.try_get(6)?,
+        won: row.try_get(7)?,
+        played_at: row.try_get(8)?
+    })
+}
+
+/// Prepare and run a query, failing with what we were trying to do
+fn run_query(db_client : &mut Client, query : &str, params : &[&(dyn ToSql + Sync)], doing : &str) -> Result<Vec<postgres::Row>, SelectorError> {
+    let statement = db_client.prepare(query).map_err(|error| SelectorError::Query(format!("Failed to {}: {}", doing, error)))?;
+    return db_client.query(&statement, params).map_err(|error| SelectorError::Query(format!("Failed to {}: {}", doing, error)));
+}
+
+fn scorecards_from_rows(rows : &[postgres::Row], doing : &str) -> Result<Vec<Scorecard>, SelectorError> {
+    return rows.iter().map(scorecard_from_row).collect::<Result<Vec<Scorecard>, _>>()
+        .map_err(|error| SelectorError::Query(format!("Failed to {}: {}", doing, error)));
+}
+
+/// Every scorecard for the given players, newest first
+pub fn query_player_scorecards(db_client : &mut Client, player_ids : &[u64]) -> Result<Vec<Scorecard>, SelectorError> {
+    let query = format!("{} WHERE s.player_id = ANY($1::bigint[]) ORDER BY s.game_datetime DESC", SCORECARD_SELECT);
+    let ids: Vec<i64> = player_ids.iter().map(|id| *id as i64).collect();
+    let results = run_query(db_client, &query, &[&ids], "retrieve player stats")?;
+
+    return scorecards_from_rows(&results, "retrieve player stats");
+}
+
+/// Scorecards for the given players from games played at or after since (seconds since the Unix epoch), newest first
+pub fn query_player_scorecards_since(db_client : &mut Client, player_ids : &[u64], since : i64) -> Result<Vec<Scorecard>, SelectorError> {
+    let query = format!("{} WHERE s.player_id = ANY($1::bigint[]) AND s.game_datetime >= to_timestamp($2::bigint) ORDER BY s.game_datetime DESC", SCORECARD_SELECT);
+    let ids: Vec<i64> = player_ids.iter().map(|id| *id as i64).collect();
+    let results = run_query(db_client, &query, &[&ids, &since], "retrieve player stats")?;
+
+    return scorecards_from_rows(&results, "retrieve player stats");
+}
+
+/// Every scorecard ever recorded, oldest game first with each game's scorecards together
+pub fn query_history(db_client : &mut Client) -> Result<Vec<Scorecard>, SelectorError> {
+    let query = format!("{} ORDER BY s.game_datetime ASC, s.game_id ASC", SCORECARD_SELECT);
+    let results = run_query(db_client, &query, &[], "retrieve game history")?;
+
+    return scorecards_from_rows(&results, "retrieve game history");
+}
+
+/// Average MVP and hit diff at each position across every scorecard. Positions nobody has played get 7.0 and 1.0
+pub fn query_population_stats(db_client : &mut Client) -> Result<PopulationStats, SelectorError> {
+    let query = "SELECT position, AVG(mvp_points)::float8, AVG(hit_diff)::float8 FROM scorecards GROUP BY position";
+    let results = run_query(db_client, query, &[], "retrieve population stats")?;
+
+    let mut population = PopulationStats { mvp: [7.0; 5], hit_diff: [1.0; 5] };
+    for row in &results {
+        let read_row = || -> Result<(String, f64, f64), postgres::Error> { Ok((row.try_get(0)?, row.try_get(1)?, row.try_get(2)?)) };
+        let (position, mvp, hit_diff) = read_row().map_err(|error| SelectorError::Query(format!("Failed to retrieve population stats: {}", error)))?;
+        if let Some(position) = position_index_from_name(&position) {
+            population.mvp[position as usize] = mvp;
+            population.hit_diff[position as usize] = hit_diff;
+        }
+    }
+
+    return Ok(population);
+}
+
+/// The ids of every player who has played under this name, ignoring case
+pub fn query_player_ids_by_name(db_client : &mut Client, player_name : &str) -> Result<Vec<u64>, SelectorError> {
+    let query = "SELECT DISTINCT s.player_id::bigint FROM scorecards s WHERE LOWER(s.player_name) = LOWER($1)";
+    let results = run_query(db_client, query, &[&player_name], "look up player by name")?;
+
+    return results.iter()
+        .map(|row| row.try_get::<_, i64>(0).map(|player_id| player_id as u64))
+        .collect::<Result<Vec<u64>, _>>()
+        .map_err(|error| SelectorError::Query(format!("Failed to look up player by name: {}", error)));
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+
+/// Everything that can stop a selection, train or backtest. Each kind has its own exit code so callers can tell them
+/// apart without reading the message. Exit code 1 is left for bugs (panics) and 2 for bad command line arguments
+#[derive(Debug)]
+pub enum SelectorError {
+    /// Missing or invalid settings, or a file we were pointed at can't be read or written
+    Config(String),
+    /// The data source can't be reached
+    Connection(String),
+    /// The data source was reached but a query on it failed
+    Query(String),
+    NotEnoughPlayers { needed: usize, given: usize },
+    /// A player name matched nobody, or more than one player
+    UnknownPlayer(String),
+    /// Position or team modifiers that can't all be honoured
+    InvalidModifier(String),
+    UnsupportedAlgorithm(String),
+    /// Training could not produce a usable model
+    Training(String),
+    /// A JSON selection request that doesn't match the schema
+    InvalidRequest(String),
+    /// A player's stats give an SMVP that can't be compared, like NaN
+    InvalidStats(String)
+}
+
+impl SelectorError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SelectorError::Config(_) => 3,
+            SelectorError::Connection(_) => 4,
+            SelectorError::Query(_) => 5,
+            SelectorError::NotEnoughPlayers { .. } => 6,
+            SelectorError::UnknownPlayer(_) => 7,
+            SelectorError::InvalidModifier(_) => 8,
+            SelectorError::UnsupportedAlgorithm(_) => 9,
+            SelectorError::Training(_) => 10,
+            SelectorError::InvalidRequest(_) => 11,
+            SelectorError::InvalidStats(_) => 12
+        }
+    }
+
+    /// Short name for the kind of error, for the JSON error output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SelectorError::Config(_) => "config",
+            SelectorError::Connection(_) => "connection",
+            SelectorError::Query(_) => "query",
+            SelectorError::NotEnoughPlayers { .. } => "not_enough_players",
+            SelectorError::UnknownPlayer(_) => "unknown_player",
+            SelectorError::InvalidModifier(_) => "invalid_modifier",
+            SelectorError::UnsupportedAlgorithm(_) => "unsupported_algorithm",
+            SelectorError::Training(_) => "training",
+            SelectorError::InvalidRequest(_) => "invalid_request",
+            SelectorError::InvalidStats(_) => "invalid_stats"
+        }
+    }
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelectorError::NotEnoughPlayers { needed, given } => {
+                write!(f, "Not enough players were given to create teams, {} are needed but only {} were given.", needed, given)
+            }
+            SelectorError::Config(message)
+            | SelectorError::Connection(message)
+            | SelectorError::Query(message)
+            | SelectorError::UnknownPlayer(message)
+            | SelectorError::InvalidModifier(message)
+            | SelectorError::UnsupportedAlgorithm(message)
+            | SelectorError::Training(message)
+            | SelectorError::InvalidRequest(message)
+            | SelectorError::InvalidStats(message) => write!(f, "{}", message)
+        }
+    }
+}
+
+impl std::error::Error for SelectorError {}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use crate::{error::SelectorError, ratings::RatingTracker, selection::retrieve_stat_block_from_position, source::DataSource, stats::{build_player_stats, needs_full_history, position_index_from_name, retrieve_stats_batch, unknown_players, PopulationTotals}, PlayerStats, PopulationStats, Scorecard, StatsParameters};
+
+/// Every scorecard from one game
+pub struct HistoricalGame {
+    pub game_id: i64,
+    pub scorecards: Vec<Scorecard>
+}
+
+/// One side of a historical game
+pub struct HistoricalTeam<'a> {
+    pub won: bool,
+    pub scorecards: Vec<&'a Scorecard>
+}
+
+/// Per position hit_diff * mvp totals, then total games played at those positions - one team's side of the prediction model
+pub type TeamFeatures = [f64; 6];
+
+/// Every game in the data source, oldest first
+pub fn retrieve_history(source : &mut dyn DataSource) -> Result<Vec<HistoricalGame>, SelectorError> {
+    return Ok(group_games(source.history()?));
+}
+
+/// Stats for the given players with any calculation mode, in the same order as player_ids. Glicko ratings replay every game,
+/// the other modes only read the players' own games
+pub fn retrieve_player_stats(source : &mut dyn DataSource, player_ids : &[u64], stats_params : &StatsParameters) -> Result<Vec<PlayerStats>, SelectorError> {
+    if needs_full_history(&stats_params.mvp_calc_mode) {
+        let tracker = StatsTracker::from_history(&retrieve_history(source)?);
+        let unknown: Vec<u64> = player_ids.iter().filter(|player_id| !tracker.scorecards.contains_key(player_id)).copied().collect();
+        if !unknown.is_empty() {
+            return Err(unknown_players(&unknown));
+        }
+        return Ok(player_ids.iter().map(|player_id| tracker.stats_before(*player_id, stats_params)).collect());
+    }
+    return retrieve_stats_batch(source, player_ids, stats_params);
+}
+
+/// Gather ordered scorecards into games, a new game starts whenever the game id changes
+fn group_games(scorecards : Vec<Scorecard>) -> Vec<HistoricalGame> {
+    let mut games: Vec<HistoricalGame> = Vec::new();
+
+    for scorecard in scorecards {
+        match games.last_mut() {
+            Some(game) if game.game_id == scorecard.game_id => game.scorecards.push(scorecard),
+            _ => games.push(HistoricalGame { game_id: scorecard.game_id, scorecards: vec![scorecard] })
+        }
+    }
+
+    return games;
+}
+
+/// Split a game into its two teams, ordered by team name. Games without exactly two teams can't be used
+pub fn split_teams(game : &HistoricalGame) -> Option<[HistoricalTeam<'_>; 2]> {
+    let mut team_names: Vec<&str> = game.scorecards.iter().map(|card| card.team.as_str()).collect();
+    team_names.sort();
+    team_names.dedup();
+
+    if team_names.len() != 2 {
+        return None;
+    }
+
+    let team = |name : &str| {
+        let scorecards: Vec<&Scorecard> = game.scorecards.iter().filter(|card| card.team == name).collect();
+        HistoricalTeam { won: scorecards.iter().any(|card| card.won), scorecards }
+    };
+
+    Some([team(team_names[0]), team(team_names[1])])
+}
+
+/// Follows every player through history in order, so their stats can be taken as they were going into any game
+pub struct StatsTracker {
+    /// Oldest first, so recording a game only appends. Read back in reverse for build_player_stats
+    scorecards: HashMap<u64, Vec<Scorecard>>,
+    ratings: RatingTracker,
+    population: PopulationTotals,
+    /// Worked out from population once per game instead of for every player looked up
+    population_averages: PopulationStats
+}
+
+impl Default for StatsTracker {
+    fn default() -> StatsTracker {
+        let population = PopulationTotals::default();
+        StatsTracker {
+            scorecards: HashMap::new(),
+            ratings: RatingTracker::default(),
+            population_averages: population.averages(),
+            population
+        }
+    }
+}
+
+impl StatsTracker {
+    /// Follow every game in history, leaving everyone's stats as they are now
+    pub fn from_history(history : &[HistoricalGame]) -> StatsTracker {
+        let mut tracker = StatsTracker::default();
+        for game in history {
+            tracker.record(game);
+        }
+        tracker
+    }
+
+    pub fn stats_before(&self, player_id : u64, stats_params : &StatsParameters) -> PlayerStats {
+        let scorecards = self.scorecards.get(&player_id).map(|cards| cards.as_slice()).unwrap_or(&[]);
+
+        if needs_full_history(&stats_params.mvp_calc_mode) {
+            let player_name = scorecards.last().map(|card| card.player_name.clone()).unwrap_or(String::from("Unknown Player"));
+            return self.ratings.player_stats(player_id, player_name);
+        }
+
+        build_player_stats(player_id, scorecards.iter().rev(), stats_params, &self.population_averages)
+    }
+
+    /// Add a finished game to everyone's history
+    pub fn record(&mut self, game : &HistoricalGame) {
+        self.ratings.record(game);
+        for card in &game.scorecards {
+            self.population.add(card);
+            self.scorecards.entry(card.player_id).or_default().push(card.clone());
+        }
+        self.population_averages = self.population.averages();
+    }
+
+    /// The prediction model's inputs for one team, using only games before this one
+    pub fn team_features(&self, team : &HistoricalTeam, stats_params : &StatsParameters) -> TeamFeatures {
+        let mut features = [0.0; 6];
+        for card in &team.scorecards {
+            let Some(position) = position_index_from_name(&card.position) else { continue };
+            let stats = retrieve_stat_block_from_position(position, self.stats_before(card.player_id, stats_params));
+            features[position as usize] += stats.hit_diff * stats.mvp;
+            features[5] += stats.games_played as f64;
+        }
+        features
+    }
+}
@@ -0,0 +1,641 @@
+//! Team selection for Space Marines 5. Build a game with create_game, load players' stats with the stats module (or make
+//! your own PlayerStats), then hand both to select_teams. select_players does all of that from player ids and names.
+
+#![allow(clippy::needless_return)]
+
+pub mod selection;
+pub mod stats;
+pub mod db;
+pub mod output;
+pub mod prediction;
+pub mod history;
+pub mod train;
+pub mod backtest;
+pub mod ratings;
+pub mod request;
+pub mod server;
+pub mod source;
+pub mod cache;
+pub mod error;
+#[cfg(test)]
+mod test_support;
+
+use std::{collections::HashSet, fmt, path::PathBuf, str::FromStr, time::Duration};
+use rand::{rngs::StdRng, SeedableRng};
+use prediction::PredictionModel;
+use source::DataSource;
+
+pub use error::SelectorError;
+use serde::{Deserialize, Serialize};
+
+/// Where scorecards come from
+#[derive(Debug, Clone)]
+pub enum DataSourceKind {
+    Postgres,
+    File(PathBuf)
+}
+
+impl FromStr for DataSourceKind {
+    type Err = String;
+
+    fn from_str(value : &str) -> Result<Self, Self::Err> {
+        if value == "postgres" {
+            return Ok(DataSourceKind::Postgres);
+        }
+        match value.strip_prefix("file:") {
+            Some(path) if !path.is_empty() => Ok(DataSourceKind::File(PathBuf::from(path))),
+            _ => Err(format!("expected postgres or file:<path>, got {}", value))
+        }
+    }
+}
+
+/// Where scorecards are read from, and whether they are cached locally
+#[derive(Debug, Clone)]
+pub struct SourceOptions {
+    pub data_source: DataSourceKind,
+    /// Keep players' scorecards in a SQLite database at this path
+    pub cache: Option<PathBuf>,
+    /// How many seconds cached scorecards are used for before checking the data source for new games
+    pub cache_max_age: u64
+}
+
+/// Database connection settings. Each one that is set overrides its environment variable, see the README
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseOptions {
+    pub port: Option<u16>,
+    pub ssl_mode: Option<SslMode>,
+    pub ca_cert: Option<PathBuf>,
+    pub connect_timeout: Option<u64>,
+    pub accept_invalid_certs: bool
+}
+
+/// Choices picked by name on the command line and in requests, like "optimal-selection"
+pub trait NamedOption: Sized + Clone + 'static {
+    /// Every choice, in the order they are listed
+    const ALL: &'static [Self];
+
+    fn name(&self) -> &'static str;
+
+    /// Find a choice by name, ignoring case
+    fn from_name(name : &str) -> Option<Self> {
+        return Self::ALL.iter().find(|option| option.name().eq_ignore_ascii_case(name)).cloned();
+    }
+}
+
+/// Implement NamedOption and Display for an enum of unit variants, given each variant's name
+macro_rules! named_options {
+    ($type:ty { $($variant:ident => $name:literal),* $(,)? }) => {
+        impl NamedOption for $type {
+            const ALL: &'static [Self] = &[$(Self::$variant),*];
+
+            fn name(&self) -> &'static str {
+                match self {
+                    $(Self::$variant => $name),*
+                }
+            }
+        }
+
+        impl fmt::Display for $type {
+            fn fmt(&self, formatter : &mut fmt::Formatter<'_>) -> fmt::Result {
+                formatter.write_str(self.name())
+            }
+        }
+    };
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub enum SslMode {
+    Disable,
+    Prefer,
+    Require,
+    Verify_Ca,
+    #[default]
+    Verify_Full,
+}
+
+named_options!(SslMode { Disable => "disable", Prefer => "prefer", Require => "require", Verify_Ca => "verify-ca", Verify_Full => "verify-full" });
+
+#[derive(Default, Debug, Clone)]
+#[allow(non_camel_case_types)]
+pub enum GameType {
+    #[default]
+    Sm5_12_Player,
+    Sm5_14_Player,
+    Sm5_10_Player,
+    Sm5_QueenBee,
+}
+
+named_options!(GameType { Sm5_12_Player => "sm5-12-player", Sm5_14_Player => "sm5-14-player", Sm5_10_Player => "sm5-10-player", Sm5_QueenBee => "sm5-queen-bee" });
+
+#[derive(Default, Debug, Clone)]
+#[allow(non_camel_case_types)]
+pub enum Algorithm {
+    Random_Random,
+    Simple_Selection,
+    #[default]
+    Advanced_Selection,
+    Optimal_Selection,
+    AI_Selection
+}
+
+named_options!(Algorithm {
+    Random_Random => "random-random",
+    Simple_Selection => "simple-selection",
+    Advanced_Selection => "advanced-selection",
+    Optimal_Selection => "optimal-selection",
+    AI_Selection => "ai-selection"
+});
+
+#[derive(Default, Debug, Clone)]
+#[allow(non_camel_case_types)]
+pub enum MVPCalculationMode {
+    #[default]
+    Median,
+    Mean,
+    Last_N_Median,
+    Last_N_Mean,
+    Glicko,
+    Shrinkage,
+    Time_Decay,
+}
+
+named_options!(MVPCalculationMode {
+    Median => "median",
+    Mean => "mean",
+    Last_N_Median => "last-n-median",
+    Last_N_Mean => "last-n-mean",
+    Glicko => "glicko",
+    Shrinkage => "shrinkage",
+    Time_Decay => "time-decay"
+});
+
+#[derive(Default, Debug, Clone)]
+#[allow(non_camel_case_types)]
+pub enum NGamesScope {
+    #[default]
+    Position,
+    Overall,
+}
+
+named_options!(NGamesScope { Position => "position", Overall => "overall" });
+
+#[derive(Clone, Serialize)]
+pub struct PlayerSlot {
+    pub position: u64,
+    pub position_pretty: String, 
+    pub player_id: i64,
+    pub smvp: f64,
+    pub player_name: String
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct PositionStats {
+    pub mvp: f64,
+    pub hit_diff: f64,
+    pub games_played: u64
+}
+
+/// A single row from the scorecards table
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Scorecard {
+    pub mvp_points: f64,
+    pub hit_diff: f64,
+    pub position: String,
+    pub player_name: String,
+    pub player_id: u64,
+    pub game_id: i64,
+    pub team: String,
+    pub won: bool,
+    /// When the game was played, in seconds since the Unix epoch
+    #[serde(default)]
+    pub played_at: i64
+}
+
+/// Settings for working out players' stats. `new` checks them, so build them with that
+#[derive(Debug, Clone)]
+pub struct StatsParameters {
+    pub mvp_calc_mode: MVPCalculationMode,
+    /// If using a last-n mode, how many games to include
+    pub n_games: u64,
+    /// If using a last-n mode, count the last n games at each position or the last n games overall
+    pub n_games_scope: NGamesScope,
+    /// If using the shrinkage mode, how many games of evidence the population average counts as
+    pub shrinkage_strength: f64,
+    /// If using the time-decay mode, how many days it takes for a game to count half as much
+    pub half_life_days: f64
+}
+
+impl StatsParameters {
+    pub fn new(mvp_calc_mode : MVPCalculationMode, n_games : u64, n_games_scope : NGamesScope, shrinkage_strength : f64, half_life_days : f64) -> Result<StatsParameters, SelectorError> {
+        if n_games == 0 {
+            return Err(SelectorError::Config(String::from("The number of games must be more than 0.")));
+        }
+        if half_life_days.is_nan() || half_life_days <= 0.0 {
+            return Err(SelectorError::Config(format!("The half life must be more than 0 days, got {}.", half_life_days)));
+        }
+        // With no games at a position, a strength of 0 would average nothing and give NaN stats
+        if shrinkage_strength.is_nan() || shrinkage_strength <= 0.0 {
+            return Err(SelectorError::Config(format!("The shrinkage strength must be more than 0 games, got {}.", shrinkage_strength)));
+        }
+        return Ok(StatsParameters { mvp_calc_mode, n_games, n_games_scope, shrinkage_strength, half_life_days });
+    }
+}
+
+/// Average MVP and hit diff at each position across every player, by position index
+#[derive(Clone, Copy)]
+pub struct PopulationStats {
+    pub mvp: [f64; 5],
+    pub hit_diff: [f64; 5]
+}
+
+pub struct DatabaseParameters {
+    pub host: String,
+    pub port: u16,
+    pub dbname: String,
+    pub user: String,
+    pub password: String,
+    pub ssl_mode: SslMode,
+    pub ca_cert: Option<PathBuf>,
+    pub connect_timeout: Option<Duration>,
+    pub accept_invalid_certs: bool
+}
+
+#[derive(Clone, Serialize)]
+pub struct PlayerStats {
+    pub player_id: u64,
+    pub player_name: String,
+    pub commander_stats: PositionStats,
+    pub heavy_stats: PositionStats,
+    pub scout_stats: PositionStats,
+    pub ammo_stats: PositionStats,
+    pub medic_stats: PositionStats,
+    //total_games_played: u64
+}
+
+/// Everything select_teams needs to make a selection
+#[derive(Clone)]
+pub struct SelectionRequest {
+    pub game_type: GameType,
+    pub team_count: u8,
+    pub algorithm: Algorithm,
+    pub players: Vec<PlayerStats>,
+    /// Pairs of player id then position index
+    pub modifier_position: Option<Vec<u64>>,
+    /// Pairs of player id then team index
+    pub modifier_team: Option<Vec<u64>>,
+    pub model: PredictionModel,
+    /// The same seed and request always give the same selection
+    pub seed: u64,
+    /// How many lineups to return, fairest first
+    pub candidates: usize,
+    /// How long optimal selection may search for
+    pub time_budget: Duration,
+    /// Refine each lineup with up to this many swaps, or leave them as the algorithm made them
+    pub refine_iterations: Option<u64>
+}
+
+/// Who to make teams out of and how, by player id and name, for select_players
+#[derive(Debug, Clone)]
+pub struct SelectionSettings {
+    pub game_type: GameType,
+    pub team_count: u8,
+    pub algorithm: Algorithm,
+    pub player_ids: Vec<u64>,
+    /// Looked up in the data source, each name must match exactly one player
+    pub player_names: Vec<String>,
+    /// Players with no games yet, by name
+    pub new_players: Vec<String>,
+    /// Pairs of player id then position index
+    pub modifier_position: Option<Vec<u64>>,
+    /// Pairs of player id then team index
+    pub modifier_team: Option<Vec<u64>>,
+    pub stats: StatsParameters,
+    /// A model file made by train, or the built in formula if not given
+    pub model: Option<PathBuf>,
+    /// A random seed is picked if not given
+    pub seed: Option<u64>,
+    pub candidates: usize,
+    pub time_budget: Duration,
+    pub refine_iterations: Option<u64>
+}
+
+/// How much refining a lineup lowered its team SMVP imbalance
+#[derive(Clone, Copy, Serialize)]
+pub struct Refinement {
+    pub imbalance_before: f64,
+    pub imbalance_after: f64
+}
+
+#[derive(Clone)]
+pub struct RankedLineup {
+    /// Team imbalance plus slot mismatch, lower is fairer
+    pub score: f64,
+    pub teams: Vec<Vec<PlayerSlot>>,
+    pub refinement: Option<Refinement>
+}
+
+/// The result of select_teams, lineups ranked fairest first with repeats dropped
+#[derive(Clone)]
+pub struct Selection {
+    pub seed: u64,
+    pub lineups: Vec<RankedLineup>,
+    /// Optimal selection ran out of time budget, so there may be fairer lineups than these
+    pub search_timed_out: bool
+}
+
+/// How many players a game type needs with this many teams
+pub fn expected_player_count(game_type : &GameType, team_count : u8) -> usize {
+    let players_per_team = match game_type {
+        GameType::Sm5_12_Player => 6,
+        GameType::Sm5_10_Player => 5,
+        GameType::Sm5_14_Player => 7,
+        GameType::Sm5_QueenBee => 4
+    };
+    return players_per_team * team_count as usize;
+}
+
+/// Look the players up in the data source and make teams out of them. Names are resolved, everyone's stats loaded, the model
+/// loaded and a seed picked if none was given. The model is returned too, the output functions need it for predictions
+pub fn select_players(source : &mut dyn DataSource, settings : &SelectionSettings) -> Result<(Selection, PredictionModel), SelectorError> {
+    // Checked before any stats are loaded, so a short list fails without waiting on the database
+    let given = settings.player_ids.len() + settings.player_names.len() + settings.new_players.len();
+    let needed = expected_player_count(&settings.game_type, settings.team_count);
+    if given < needed {
+        return Err(SelectorError::NotEnoughPlayers { needed, given });
+    }
+
+    let mut player_ids = settings.player_ids.clone();
+    player_ids.extend(stats::resolve_player_names(source, &settings.player_names)?);
+
+    let mut players = history::retrieve_player_stats(source, &player_ids, &settings.stats)?;
+    for player in &settings.new_players {
+        players.push(stats::retrieve_stats_new(player.clone()));
+    }
+
+    let model = match &settings.model {
+        Some(path) => prediction::load_model(path)?,
+        None => PredictionModel::default()
+    };
+
+    let selection = select_teams(SelectionRequest {
+        game_type: settings.game_type.clone(),
+        team_count: settings.team_count,
+        algorithm: settings.algorithm.clone(),
+        players,
+        modifier_position: settings.modifier_position.clone(),
+        modifier_team: settings.modifier_team.clone(),
+        model: model.clone(),
+        // Generated seeds stay small so they survive a round trip through JSON numbers and are easy to type back in
+        seed: settings.seed.unwrap_or_else(|| rand::random::<u32>() as u64),
+        candidates: settings.candidates,
+        time_budget: settings.time_budget,
+        refine_iterations: settings.refine_iterations
+    })?;
+
+    return Ok((selection, model));
+}
+
+/// Make teams out of the request's players
+pub fn select_teams(request : SelectionRequest) -> Result<Selection, SelectorError> {
+    if request.team_count == 0 {
+        return Err(SelectorError::InvalidRequest(String::from("The team count must be at least 1.")));
+    }
+    if request.candidates == 0 {
+        return Err(SelectorError::InvalidRequest(String::from("At least 1 candidate lineup must be asked for.")));
+    }
+
+    // The algorithms take players out of the pool by id, so the same player twice would end up on two teams
+    let mut player_ids = HashSet::new();
+    for player in &request.players {
+        if !player_ids.insert(player.player_id) {
+            return Err(SelectorError::InvalidRequest(format!("Player {} ({}) was given more than once.", player.player_name, player.player_id)));
+        }
+    }
+
+    let needed = expected_player_count(&request.game_type, request.team_count);
+    if request.players.len() < needed {
+        return Err(SelectorError::NotEnoughPlayers { needed, given: request.players.len() });
+    }
+
+    let mut rng = StdRng::seed_from_u64(request.seed);
+    let game = create_game(request.game_type.clone(), request.team_count);
+    let model = &request.model;
+
+    // Optimal selection finds its best few lineups in one search, everything else just runs again
+    let (lineups, search_timed_out) = match request.algorithm {
+        Algorithm::Optimal_Selection => {
+            selection::optimal_selection(game, request.players.clone(), &request.modifier_position, &request.modifier_team, model, request.time_budget, request.candidates)?
+        }
+        _ => {
+            let lineups = (0..request.candidates)
+                .map(|_| run_algorithm(&request.algorithm, game.clone(), request.players.clone(), &request.modifier_position, &request.modifier_team, model, &mut rng))
+                .collect::<Result<Vec<_>, _>>()?;
+            (lineups, false)
+        }
+    };
+
+    let lineups: Vec<(Vec<Vec<PlayerSlot>>, Option<Refinement>)> = lineups.into_iter().map(|lineup| match request.refine_iterations {
+        Some(max_iterations) => {
+            let (refined_game, imbalance_before, imbalance_after) = selection::refine_swaps(lineup, &request.players, &request.modifier_position, &request.modifier_team, model, max_iterations)?;
+            Ok((refined_game, Some(Refinement { imbalance_before, imbalance_after })))
+        }
+        None => Ok((lineup, None))
+    }).collect::<Result<_, SelectorError>>()?;
+
+    return Ok(Selection { seed: request.seed, lineups: selection::rank_lineups(lineups), search_timed_out });
+}
+
+/// Run one of the single lineup algorithms once
+fn run_algorithm(algorithm : &Algorithm, game : Vec<Vec<PlayerSlot>>, players : Vec<PlayerStats>, modifiers_position : &Option<Vec<u64>>, modifiers_team : &Option<Vec<u64>>, model : &PredictionModel, rng : &mut StdRng) -> Result<Vec<Vec<PlayerSlot>>, SelectorError> {
+    match algorithm {
+        Algorithm::Advanced_Selection => {
+            return selection::calculate_advanced(game, players, modifiers_position, modifiers_team, model, rng);
+        }
+        Algorithm::Optimal_Selection => {
+            unreachable!("Optimal selection returns all of its lineups from one search.");
+        }
+        Algorithm::AI_Selection => {
+            return Err(SelectorError::UnsupportedAlgorithm(String::from("AI assisted selection is not here yet, pick another algorithm.")));
+        }
+        Algorithm::Random_Random => {
+            return selection::random_random(game, players, modifiers_position, modifiers_team, model, rng);
+        }
+        Algorithm::Simple_Selection => {
+            return selection::simple_selection(game, players, modifiers_position, modifiers_team, model);
+        }
+    }
+}
+
+pub fn create_game(game : GameType, team_count : u8) -> Vec<Vec<PlayerSlot>> {
+    match game {
+        GameType::Sm5_12_Player => {
+
+            let postion_array = vec![0, 1, 2, 2, 3, 4];
+            let mut final_vec: Vec<Vec<PlayerSlot>> = Vec::new();
+            for _team in 0..team_count {
+
+                let mut player_vec: Vec<PlayerSlot> = Vec::new();
+                for player in &postion_array {
+                    player_vec.push(
+                        PlayerSlot { 
+                            position: *player,
+                            position_pretty: stats::set_position_from_index(*player),
+                            player_id: -1,
+                            smvp: -1.0,
+                            player_name : "Unfilled slot! Report to Metalface!".to_string()
+                        }
+                    );
+                }
+                final_vec.push(player_vec);
+            }
+
+            return final_vec;
+        }
+
+        GameType::Sm5_10_Player => {
+    
+            let postion_array = vec![0, 1, 2, 3, 4];
+            let mut final_vec: Vec<Vec<PlayerSlot>> = Vec::new();
+            for _team in 0..team_count {
+
+                let mut player_vec: Vec<PlayerSlot> = Vec::new();
+                for player in &postion_array {
+                    player_vec.push(
+                        PlayerSlot { 
+                            position: *player,
+                            position_pretty: stats::set_position_from_index(*player),
+                            player_id: -1,
+                            smvp: -1.0,
+                            player_name : "Unfilled slot! Report to Metalface!".to_string()
+                        }
+                    );
+                }
+                final_vec.push(player_vec);
+            }
+
+            return final_vec;
+        }
+
+        GameType::Sm5_14_Player => {
+
+            let postion_array = vec![0, 1, 2, 2, 2, 3, 4];
+            let mut final_vec: Vec<Vec<PlayerSlot>> = Vec::new();
+            for _team in 0..team_count {
+
+                let mut player_vec: Vec<PlayerSlot> = Vec::new();
+                for player in &postion_array {
+                    player_vec.push(
+                        PlayerSlot { 
+                            position: *player,
+                            position_pretty: stats::set_position_from_index(*player),
+                            player_id: -1,
+                            smvp: -1.0,
+                            player_name : "Unfilled slot! Report to Metalface!".to_string()
+                        }
+                    );
+                }
+                final_vec.push(player_vec);
+            }
+
+            return final_vec;
+        }
+
+        GameType::Sm5_QueenBee => {
+            let postion_array = vec![2, 2, 3, 4];
+            let mut final_vec: Vec<Vec<PlayerSlot>> = Vec::new();
+            for _team in 0..team_count {
+
+                let mut player_vec: Vec<PlayerSlot> = Vec::new();
+                for player in &postion_array {
+                    player_vec.push(
+                        PlayerSlot { 
+                            position: *player,
+                            position_pretty: stats::set_position_from_index(*player),
+                            player_id: -1,
+                            smvp: -1.0,
+                            player_name : "Unfilled slot! Report to Metalface!".to_string()
+                        }
+                    );
+                }
+                final_vec.push(player_vec);
+            }
+            return final_vec;
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{random_players, scorecard, temp_file};
+
+    fn selected_ids(algorithm : Algorithm, seed : u64) -> Vec<Vec<Vec<i64>>> {
+        let selection = select_teams(SelectionRequest {
+            game_type: GameType::Sm5_12_Player,
+            team_count: 2,
+            algorithm,
+            players: random_players(12, 0),
+            modifier_position: None,
+            modifier_team: None,
+            model: PredictionModel::default(),
+            seed,
+            candidates: 3,
+            time_budget: Duration::from_secs(1),
+            refine_iterations: Some(20)
+        }).unwrap();
+        assert_eq!(selection.seed, seed);
+        return selection.lineups.iter()
+            .map(|lineup| lineup.teams.iter().map(|team| team.iter().map(|slot| slot.player_id).collect()).collect())
+            .collect();
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_selection() {
+        for algorithm in [Algorithm::Random_Random, Algorithm::Advanced_Selection] {
+            for seed in 0..5 {
+                assert_eq!(selected_ids(algorithm.clone(), seed), selected_ids(algorithm.clone(), seed), "{} seed {}", algorithm, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn different_seeds_give_different_random_selections() {
+        let first = selected_ids(Algorithm::Random_Random, 0);
+        assert!((1..5).any(|seed| selected_ids(Algorithm::Random_Random, seed) != first));
+    }
+
+    #[test]
+    fn select_players_looks_players_up() {
+        // Ten players who have each played one game at every position
+        let scorecards: Vec<Scorecard> = (1..=10u64).flat_map(|player_id| (0..5u64).map(move |position| {
+            scorecard(player_id, &stats::set_position_from_index(position), (player_id + position) as f64, position as i64, if player_id <= 5 { "red" } else { "green" }, player_id <= 5)
+        })).collect();
+        let path = temp_file("select-players.json", &serde_json::to_string(&scorecards).unwrap());
+        let mut source = source::FileSource::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let settings = SelectionSettings {
+            game_type: GameType::Sm5_12_Player,
+            team_count: 2,
+            algorithm: Algorithm::Simple_Selection,
+            player_ids: (1..=9).collect(),
+            player_names: vec![String::from("P10")],
+            new_players: vec![String::from("Newcomer"), String::from("Another")],
+            modifier_position: None,
+            modifier_team: None,
+            stats: StatsParameters::new(MVPCalculationMode::Mean, 50, NGamesScope::default(), 10.0, 365.0).unwrap(),
+            model: None,
+            seed: Some(3),
+            candidates: 1,
+            time_budget: Duration::from_secs(1),
+            refine_iterations: None
+        };
+        let (selection, _) = select_players(&mut source, &settings).unwrap();
+
+        assert_eq!(selection.seed, 3);
+        let slots: Vec<&PlayerSlot> = selection.lineups[0].teams.iter().flatten().collect();
+        assert!(slots.iter().any(|slot| slot.player_id == 10));
+        assert_eq!(slots.iter().filter(|slot| slot.player_name == "Newcomer" || slot.player_name == "Another").count(), 2);
+        assert!(slots.iter().all(|slot| slot.position_pretty == stats::set_position_from_index(slot.position)));
+    }
+}
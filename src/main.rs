@@ -1,38 +1,53 @@
-mod selection;
-mod stats;
-mod db;
-mod output;
-mod prediction;
-
-use core::panic;
-use clap::Parser;
-use output::{output_game_as_json, output_game_text};
-use serde::Serialize;
+#![allow(clippy::needless_return)]
+
+use std::{fs::File, io::{self, BufRead, BufReader, BufWriter, Write}, path::{Path, PathBuf}, time::Duration};
+use clap::{builder::{PossibleValuesParser, TypedValueParser}, Parser};
+use serde_json::json;
+use selector_backend::{backtest, history, prediction, select_players, source, train, Algorithm, DataSourceKind, DatabaseOptions, GameType, MVPCalculationMode, NGamesScope, NamedOption, Selection, SelectionSettings, SelectorError, SourceOptions, SslMode, StatsParameters};
+use selector_backend::backtest::{AlgorithmReport, MethodReport};
+use selector_backend::output::{batch_error_line, batch_selection_line, error_as_json, selection_as_json, selection_as_text};
+use selector_backend::prediction::PredictionModel;
+use selector_backend::request::{self, SelectionInput};
+use selector_backend::server::{self, RequestLimits, ServerOptions};
+use selector_backend::source::{DataSource, MemorySource};
 
 /// The backend of the Team Selector by Metalface - Intended to be used by the website
-#[derive(Parser, Debug)]
-#[command(version, long_about=None)]
+#[derive(Parser, Debug, Clone)]
+#[command(version, long_about=None, args_conflicts_with_subcommands = true)]
 struct SelectorArgs {
+    #[command(subcommand)]
+    command: Option<SelectorCommand>,
+
+    #[command(flatten)]
+    source: SourceArgs,
+
+    #[command(flatten)]
+    database: DatabaseArgs,
+
     /// Select the game type to use
-    #[arg(short, long, default_value_t, value_enum)]
+    #[arg(short, long, default_value_t, value_parser = named_option::<GameType>())]
     game_type: GameType,
 
     /// Select the algorithm to use
-    #[arg(short, long, default_value_t, value_enum)]
+    #[arg(short, long, default_value_t, value_parser = named_option::<Algorithm>())]
     algorithm: Algorithm,
 
-    /// Select the MVP Calculation mode to use - Unimplemented
-    #[arg(short, long, default_value_t, value_enum)]
+    /// Select the MVP Calculation mode to use
+    #[arg(short, long, default_value_t, value_parser = named_option::<MVPCalculationMode>())]
     mvp_calculation_mode: MVPCalculationMode,
 
     /// Use team count, for some unknown reason
-    #[arg(short, long, default_value_t = 2)]
+    #[arg(short, long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..))]
     team_count: u8,
 
     /// Add a player to the selection - Takes player id
     #[arg(short, long)]
     player: Vec<u64>,
 
+    /// Add a player to the selection by their name instead of their id
+    #[arg(long)]
+    player_name: Vec<String>,
+
     /// Add a new player to the selection - default smvp of 7
     #[arg(short='n', long)]
     new_player: Vec<String>,
@@ -41,250 +56,459 @@ struct SelectorArgs {
     #[arg(long, num_args=2, value_name = "INT")]
     modifier_position: Option<Vec<u64>>,
 
-    /// Modify a player to force them to be on a certain team - Enter player_id followed by the team index - Unimplemented
+    /// Modify a player to force them to be on a certain team - Enter player_id followed by the team index
     #[arg(long, num_args=2, value_name = "INT")]
     modifier_team: Option<Vec<u64>>,
 
-    /// If using last-n calculation mode, specify the amount of games to include - Unimplemented
-    #[arg(long, default_value_t = 50)]
-    n_games: u64,
+    /// Read the selection from a JSON request, `-` for stdin - Its lists are added to the ones given here, its other fields replace the arguments here
+    #[arg(long, value_name = "PATH")]
+    request: Option<PathBuf>,
+
+    #[command(flatten)]
+    stats: StatsArgs,
+
+    /// If using optimal selection, stop searching after this many milliseconds and use the best lineup found
+    #[arg(long, default_value_t = 2000)]
+    time_budget_ms: u64,
+
+    /// After selection, swap players between teams and positions while it lowers the team SMVP imbalance
+    #[arg(long)]
+    refine: bool,
+
+    /// If refining, the most swaps to make
+    #[arg(long, default_value_t = 100)]
+    refine_iterations: u64,
+
+    /// Seed the random number generator so a selection can be made again exactly - A random seed is used if not given
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Output this many lineups ranked fairest first, instead of just one. Optimal selection returns its best distinct lineups, other algorithms are run again
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    candidates: u64,
+
+    /// Load smvp and prediction weights from a model file made by the train command - The built in formula is used if not given
+    #[arg(long)]
+    model: Option<PathBuf>,
+
+    /// Flag a lineup as lopsided when any team's predicted chance to win is at least this
+    #[arg(long, default_value_t = 0.7)]
+    lopsided_threshold: f64,
 
     /// Select the output method to use
     #[arg(long, default_value_t, value_enum)]
     output_method: OutputMethods
 }
 
-#[derive(clap::ValueEnum, Default, Debug, Clone)]
-#[allow(non_camel_case_types)]
-enum GameType {
-    #[default]
-    Sm5_12_Player,
-    Sm5_14_Player,
-    Sm5_10_Player,
-    Sm5_QueenBee,
+/// Where scorecards are read from, and whether they are cached locally
+#[derive(clap::Args, Debug, Clone)]
+struct SourceArgs {
+    /// Where scorecards are read from, `postgres` or `file:<path>` for a JSON or CSV export
+    #[arg(long, global = true, default_value = "postgres")]
+    data_source: DataSourceKind,
+
+    /// Keep players' scorecards in a SQLite database at this path, and only fetch games newer than the cache
+    #[arg(long, global = true)]
+    cache: Option<PathBuf>,
+
+    /// How many seconds cached scorecards are used for before checking the data source for new games
+    #[arg(long, global = true, default_value_t = 3600)]
+    cache_max_age: u64,
 }
 
-#[derive(clap::ValueEnum, Default, Debug, Clone)]
-#[allow(non_camel_case_types)]
-enum OutputMethods {
-    #[default]
-    Json,
-    Plaintext
+impl SourceArgs {
+    fn options(&self) -> SourceOptions {
+        return SourceOptions { data_source: self.data_source.clone(), cache: self.cache.clone(), cache_max_age: self.cache_max_age };
+    }
 }
 
-#[derive(clap::ValueEnum, Default, Debug, Clone)]
-#[allow(non_camel_case_types)]
-enum Algorithm {
-    Random_Random,
-    Simple_Selection,
-    #[default]
-    Advanced_Selection,
-    AI_Selection
+/// Database connection settings. Each one overrides its environment variable, see the README
+#[derive(clap::Args, Debug, Clone)]
+struct DatabaseArgs {
+    /// Database port, overrides DATABASE_PORT
+    #[arg(long, global = true)]
+    db_port: Option<u16>,
+
+    /// How the database connection uses TLS, overrides DATABASE_SSLMODE
+    #[arg(long, global = true, value_parser = named_option::<SslMode>())]
+    db_sslmode: Option<SslMode>,
+
+    /// Trust the CA certificate in this PEM file when checking the database's certificate, overrides DATABASE_CA_CERT
+    #[arg(long, global = true)]
+    db_ca_cert: Option<PathBuf>,
+
+    /// Give up connecting to the database after this many seconds, overrides DATABASE_CONNECT_TIMEOUT
+    #[arg(long, global = true)]
+    db_connect_timeout: Option<u64>,
+
+    /// Accept any certificate from the database, even invalid or self signed ones. Only use this on networks you trust
+    #[arg(long, global = true)]
+    db_accept_invalid_certs: bool,
 }
 
-#[derive(clap::ValueEnum, Default, Debug, Clone)]
-#[allow(non_camel_case_types)]
-enum MVPCalculationMode {
-    #[default]
-    Median,
-    Mean,
-    Last_N_Median,
-    Last_N_Mean,
+impl DatabaseArgs {
+    fn options(&self) -> DatabaseOptions {
+        return DatabaseOptions {
+            port: self.db_port,
+            ssl_mode: self.db_sslmode,
+            ca_cert: self.db_ca_cert.clone(),
+            connect_timeout: self.db_connect_timeout,
+            accept_invalid_certs: self.db_accept_invalid_certs
+        };
+    }
+}
+
+/// Settings for the MVP calculation modes that take them
+#[derive(clap::Args, Debug, Clone)]
+struct StatsArgs {
+    /// If using last-n calculation mode, specify the amount of games to include
+    #[arg(long, default_value_t = 50)]
+    n_games: u64,
+
+    /// If using last-n calculation mode, count the last n games at each position or the last n games overall
+    #[arg(long, default_value_t, value_parser = named_option::<NGamesScope>())]
+    n_games_scope: NGamesScope,
+
+    /// If using shrinkage calculation mode, how many games of evidence the population average counts as
+    #[arg(long, default_value_t = 10.0)]
+    shrinkage_strength: f64,
+
+    /// If using time-decay calculation mode, how many days it takes for a game to count half as much
+    #[arg(long, default_value_t = 365.0)]
+    half_life_days: f64,
 }
 
-#[derive(Clone, Serialize)]
-struct PlayerSlot {
-    position: u64,
-    position_pretty: String, 
-    player_id: i64,
-    smvp: f64,
-    player_name: String
+impl StatsArgs {
+    fn parameters(&self, mvp_calc_mode : MVPCalculationMode) -> Result<StatsParameters, SelectorError> {
+        return StatsParameters::new(mvp_calc_mode, self.n_games, self.n_games_scope.clone(), self.shrinkage_strength, self.half_life_days);
+    }
 }
 
-#[derive(Clone, Copy)]
-struct PositionStats {
-    mvp: f64,
-    hit_diff: f64,
-    games_played: u64
+#[derive(clap::ValueEnum, Default, Debug, Clone)]
+enum OutputMethods {
+    #[default]
+    Json,
+    Plaintext
 }
 
-struct DatabaseParameters {
-    host: String,
-    dbname: String,
-    user: String,
-    password: String
+/// Parse one of the library's named choices, listing them all in --help
+fn named_option<T : NamedOption + Send + Sync>() -> impl TypedValueParser<Value = T> {
+    return PossibleValuesParser::new(T::ALL.iter().map(|option| option.name())).map(|name| T::from_name(&name).unwrap());
 }
 
-#[derive(Clone)]
-struct PlayerStats {
-    player_id: u64,
-    player_name: String,
-    commander_stats: PositionStats,
-    heavy_stats: PositionStats,
-    scout_stats: PositionStats,
-    ammo_stats: PositionStats,
-    medic_stats: PositionStats,
-    //total_games_played: u64
+#[derive(clap::Subcommand, Debug, Clone)]
+enum SelectorCommand {
+    /// Fit the prediction model to historical games and save the weights, for use with --model
+    Train {
+        /// Where to save the model
+        #[arg(short, long, default_value = "model.json")]
+        output: PathBuf,
+
+        /// The MVP Calculation mode used for players' stats going into each game
+        #[arg(short, long, default_value_t, value_parser = named_option::<MVPCalculationMode>())]
+        mvp_calculation_mode: MVPCalculationMode,
+
+        #[command(flatten)]
+        stats: StatsArgs,
+    },
+
+    /// Replay history and report how well each MVP calculation mode and smvp formula predicted the winners
+    Backtest {
+        /// Also test the weights in this model file against the built in formula
+        #[arg(long)]
+        model: Option<PathBuf>,
+
+        #[command(flatten)]
+        stats: StatsArgs,
+
+        /// Replay this selection algorithm on every game's players - Can be given more than once
+        #[arg(short, long, default_values_t = [Algorithm::Random_Random, Algorithm::Simple_Selection, Algorithm::Advanced_Selection], value_parser = named_option::<Algorithm>())]
+        algorithm: Vec<Algorithm>,
+
+        /// The MVP Calculation mode players' stats use when replaying selection algorithms
+        #[arg(short, long, default_value_t, value_parser = named_option::<MVPCalculationMode>())]
+        mvp_calculation_mode: MVPCalculationMode,
+
+        /// If replaying optimal selection, how many milliseconds it may search each game for
+        #[arg(long, default_value_t = 100)]
+        time_budget_ms: u64,
+
+        /// Select the output method to use
+        #[arg(long, default_value_t, value_enum)]
+        output_method: OutputMethods
+    },
+
+    /// Make teams for every selection request in a JSON Lines file, one request per line, and write one JSON line per request
+    Batch {
+        /// The file of requests, in the same format as --request - Use `-` for stdin
+        input: PathBuf,
+
+        /// Where to write the results - stdout if not given
+        #[arg(short, long)]
+        output: Option<PathBuf>
+    },
+
+    /// Run an HTTP server with POST /select, GET /players/{id}/stats and GET /health, returning the same JSON as the command line
+    Serve {
+        /// Address and port to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: String,
+
+        /// How many requests to handle at once, each worker keeps its own database connection
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+        workers: u64,
+
+        /// How many seconds players' scorecards are kept in memory before reading them again
+        #[arg(long, default_value_t = 300)]
+        stats_max_age: u64,
+
+        /// Model file made by the train command to use for every request - Requests can't name their own
+        #[arg(long)]
+        model: Option<PathBuf>,
+
+        /// The most candidate lineups a request can ask for
+        #[arg(long, default_value_t = 10)]
+        max_candidates: u64,
+
+        /// The longest time budget in milliseconds a request can ask for
+        #[arg(long, default_value_t = 5000)]
+        max_time_budget_ms: u64,
+
+        /// The most refinement iterations a request can ask for
+        #[arg(long, default_value_t = 1000)]
+        max_refine_iterations: u64
+    }
 }
 
 fn main() {
-    let args = SelectorArgs::parse();
-    let mut players: Vec<PlayerStats> = Vec::new();
-    let expected_player_count : u64;
+    let mut args = SelectorArgs::parse();
 
-    match args.game_type {
-        GameType::Sm5_12_Player => {
-            expected_player_count = 6 * args.team_count as u64;
-        }
-        
-        GameType::Sm5_10_Player => {
-            expected_player_count = 5 * args.team_count as u64;
+    if let Some(path) = args.request.clone() {
+        match request::read_request(&path) {
+            Ok(input) => apply_request(&mut args, input),
+            Err(error) => report_error(&error, &args.output_method)
         }
+    }
 
-        GameType::Sm5_14_Player => {
-            expected_player_count = 7 * args.team_count as u64;
-        }
+    // Errors are reported the same way as the command's normal output
+    let error_output_method = match &args.command {
+        Some(SelectorCommand::Train { .. }) | Some(SelectorCommand::Batch { .. }) | Some(SelectorCommand::Serve { .. }) => OutputMethods::Plaintext,
+        Some(SelectorCommand::Backtest { output_method, .. }) => output_method.clone(),
+        None => args.output_method.clone()
+    };
 
-        GameType::Sm5_QueenBee => {
-            expected_player_count = 4 * args.team_count as u64;
-        }
+    if let Err(error) = run(args) {
+        report_error(&error, &error_output_method);
     }
+}
 
-    if args.player.len() + args.new_player.len() < expected_player_count.try_into().unwrap() {
-        panic!("Not enough players were given to create teams.");
+fn report_error(error : &SelectorError, output_method : &OutputMethods) -> ! {
+    match output_method {
+        // Errors go to stdout as JSON, so the website reads them from the same place as a selection
+        OutputMethods::Json => print!("{}", error_as_json(error)),
+        OutputMethods::Plaintext => eprintln!("Error: {}", error)
     }
+    std::process::exit(error.exit_code());
+}
 
-    for player in args.player {
-        players.push(stats::retrieve_stats(player, args.mvp_calculation_mode.clone()));
+/// Fill in the arguments from a JSON selection request
+fn apply_request(args : &mut SelectorArgs, input : SelectionInput) {
+    let modifier_position = input.modifier_position();
+    let modifier_team = input.modifier_team();
+    if !modifier_position.is_empty() {
+        args.modifier_position.get_or_insert_with(Vec::new).extend(modifier_position);
     }
-
-    for player in args.new_player {
-        players.push(stats::retrieve_stats_new(player));
+    if !modifier_team.is_empty() {
+        args.modifier_team.get_or_insert_with(Vec::new).extend(modifier_team);
     }
-    
 
-    let mut game = create_game(args.game_type, args.team_count);
+    args.player.extend(input.players);
+    args.player_name.extend(input.player_names);
+    args.new_player.extend(input.new_players);
+
+    if let Some(game_type) = input.game_type { args.game_type = game_type; }
+    if let Some(algorithm) = input.algorithm { args.algorithm = algorithm; }
+    if let Some(mode) = input.mvp_calculation_mode { args.mvp_calculation_mode = mode; }
+    if let Some(team_count) = input.team_count { args.team_count = team_count; }
+
+    let options = input.options;
+    if let Some(n_games) = options.n_games { args.stats.n_games = n_games; }
+    if let Some(scope) = options.n_games_scope { args.stats.n_games_scope = scope; }
+    if let Some(strength) = options.shrinkage_strength { args.stats.shrinkage_strength = strength; }
+    if let Some(half_life) = options.half_life_days { args.stats.half_life_days = half_life; }
+    if let Some(time_budget) = options.time_budget_ms { args.time_budget_ms = time_budget; }
+    if let Some(refine) = options.refine { args.refine = refine; }
+    if let Some(iterations) = options.refine_iterations { args.refine_iterations = iterations; }
+    if let Some(candidates) = options.candidates { args.candidates = candidates; }
+    if let Some(threshold) = options.lopsided_threshold { args.lopsided_threshold = threshold; }
+    if options.seed.is_some() { args.seed = options.seed; }
+    if options.model.is_some() { args.model = options.model; }
+}
 
-    match args.algorithm {
-        Algorithm::Advanced_Selection => {
-            game = selection::calculate_advanced(game, players, &args.modifier_position, &args.modifier_team);
+fn run(args : SelectorArgs) -> Result<(), SelectorError> {
+    match args.command {
+        Some(SelectorCommand::Train { ref output, ref mvp_calculation_mode, ref stats }) => {
+            let stats_params = stats.parameters(mvp_calculation_mode.clone())?;
+            let mut source = source::open_data_source(&args.source.options(), &args.database.options())?;
+            let report = train::train_model(&history::retrieve_history(source.as_mut())?, &stats_params)?;
+            prediction::save_model(&report.model, output)?;
+
+            println!("Trained on {} games, {:.1}% predicted correctly.", report.games, report.correct as f64 / report.games as f64 * 100.0);
+            println!("Position weights: {:?}", report.model.position_weights);
+            println!("Games weight: {}", report.model.games_weight);
+            println!("Scale: {}", report.model.scale);
+            println!("Saved to {}", output.display());
+            return Ok(());
+        }
+        Some(SelectorCommand::Backtest { ref model, ref stats, ref algorithm, ref mvp_calculation_mode, time_budget_ms, ref output_method }) => {
+            let mut formulas = vec![(String::from("built-in"), PredictionModel::default())];
+            if let Some(path) = model {
+                formulas.push((path.display().to_string(), prediction::load_model(path)?));
+            }
+            let stats_params = stats.parameters(MVPCalculationMode::default())?;
+            let mut source = source::open_data_source(&args.source.options(), &args.database.options())?;
+            let history = history::retrieve_history(source.as_mut())?;
+            let reports = backtest::run_backtest(&history, &formulas, &stats_params);
+
+            // Algorithms make teams with the --model weights if given, the same as selection would
+            let replay_model = &formulas.last().unwrap().1;
+            let algorithm_reports = backtest::run_algorithm_backtest(&history, algorithm, &stats.parameters(mvp_calculation_mode.clone())?, replay_model, Duration::from_millis(time_budget_ms))?;
+            print_backtest(&reports, &algorithm_reports, output_method);
+            return Ok(());
         }
-        Algorithm::AI_Selection => {
-            unimplemented!("AI assisted selection is not here yet.");
+        Some(SelectorCommand::Batch { ref input, ref output }) => {
+            return batch(&args, input, output.as_deref());
         }
-        Algorithm::Random_Random => {
-            game = selection::random_random(game, players, &args.modifier_position, &args.modifier_team);
+        Some(SelectorCommand::Serve { ref bind, workers, stats_max_age, ref model, max_candidates, max_time_budget_ms, max_refine_iterations }) => {
+            let options = ServerOptions {
+                bind: bind.clone(),
+                workers: workers as usize,
+                stats_max_age: Duration::from_secs(stats_max_age),
+                stats: args.stats.parameters(MVPCalculationMode::default())?,
+                limits: RequestLimits { max_candidates, max_time_budget_ms, max_refine_iterations }
+            };
+            // Requests that don't set these get the defaults, which mustn't go over the limits either
+            let mut base_args = args.clone();
+            base_args.model = model.clone();
+            base_args.time_budget_ms = base_args.time_budget_ms.min(max_time_budget_ms);
+            base_args.refine_iterations = base_args.refine_iterations.min(max_refine_iterations);
+            base_args.candidates = base_args.candidates.min(max_candidates);
+            let select = |input : SelectionInput, source : &mut dyn DataSource| -> Result<String, SelectorError> {
+                let mut request_args = base_args.clone();
+                apply_request(&mut request_args, input);
+                let (selection, model) = make_selection(&request_args, source)?;
+                let seed = selection.seed;
+                Ok(selection_as_json(selection.lineups, request_args.candidates > 1, seed, &model, request_args.lopsided_threshold))
+            };
+            return server::serve(&options, &args.source.options(), &args.database.options(), &select);
         }
-        Algorithm::Simple_Selection => {
-            unimplemented!("Simple selection is not ready yet.");
+        None => {
+            return select(args);
         }
     }
+}
+
+/// The default command, make teams out of the given players
+fn select(args : SelectorArgs) -> Result<(), SelectorError> {
+    let mut source = source::open_data_source(&args.source.options(), &args.database.options())?;
+    let (selection, model) = make_selection(&args, source.as_mut())?;
 
+    let ranked = args.candidates > 1;
     match args.output_method {
-        OutputMethods::Json => {
-            output_game_as_json(game);
-        }
-        OutputMethods::Plaintext => {
-            output_game_text(game);
-        }
+        OutputMethods::Json => print!("{}", selection_as_json(selection.lineups, ranked, selection.seed, &model, args.lopsided_threshold)),
+        OutputMethods::Plaintext => print!("{}", selection_as_text(selection.lineups, ranked, selection.seed, &model, args.lopsided_threshold))
     }
 
+    return Ok(());
 }
 
-fn create_game(game : GameType, team_count : u8) -> Vec<Vec<PlayerSlot>> {
-    match game {
-        GameType::Sm5_12_Player => {
-
-            let postion_array = vec![0, 1, 2, 2, 3, 4];
-            let mut final_vec: Vec<Vec<PlayerSlot>> = Vec::new();
-            for _team in 0..team_count {
-
-                let mut player_vec: Vec<PlayerSlot> = Vec::new();
-                for player in &postion_array {
-                    player_vec.push(
-                        PlayerSlot { 
-                            position: *player,
-                            position_pretty: String::from(""),
-                            player_id: -1,
-                            smvp: -1.0,
-                            player_name : "Unfilled slot! Report to Metalface!".to_string()
-                        }
-                    );
-                }
-                final_vec.push(player_vec);
-            }
-
-            return final_vec;
+fn print_backtest(reports : &[MethodReport], algorithm_reports : &[AlgorithmReport], output_method : &OutputMethods) {
+    match output_method {
+        OutputMethods::Json => {
+            print!("{}", serde_json::to_string_pretty(&json!({ "methods": reports, "algorithms": algorithm_reports })).unwrap());
         }
-
-        GameType::Sm5_10_Player => {
-    
-            let postion_array = vec![0, 1, 2, 3, 4];
-            let mut final_vec: Vec<Vec<PlayerSlot>> = Vec::new();
-            for _team in 0..team_count {
-
-                let mut player_vec: Vec<PlayerSlot> = Vec::new();
-                for player in &postion_array {
-                    player_vec.push(
-                        PlayerSlot { 
-                            position: *player,
-                            position_pretty: String::from(""),
-                            player_id: -1,
-                            smvp: -1.0,
-                            player_name : "Unfilled slot! Report to Metalface!".to_string()
-                        }
-                    );
+        OutputMethods::Plaintext => {
+            for report in reports {
+                println!("Mode: {}, Formula: {}, Games: {}, Accuracy: {:.1}%, Brier score: {:.4}",
+                    report.mvp_calculation_mode, report.formula, report.games, report.accuracy * 100.0, report.brier_score);
+                for bucket in &report.calibration {
+                    println!("    Favourite {:.0}-{:.0}%: {} games, predicted {:.1}%, won {:.1}%",
+                        bucket.favourite_chance_from * 100.0, bucket.favourite_chance_to * 100.0, bucket.games, bucket.mean_predicted * 100.0, bucket.favourite_win_rate * 100.0);
                 }
-                final_vec.push(player_vec);
             }
+            for report in algorithm_reports {
+                println!("Algorithm: {}, Games: {}, Favourite chance: {:.1}%, Team imbalance: {:.2}, Score: {:.2}",
+                    report.algorithm, report.games, report.favourite_chance * 100.0, report.team_imbalance, report.score);
+            }
+        }
+    }
+}
 
-            return final_vec;
+/// Run every selection request in a JSON Lines file through one data source, writing a JSON line for each.
+/// A request that fails gets an error line and the rest of the batch carries on
+fn batch(args : &SelectorArgs, input : &Path, output : Option<&Path>) -> Result<(), SelectorError> {
+    let reader: Box<dyn BufRead> = if input == Path::new("-") {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(input).map_err(|error| SelectorError::Config(format!("Could not read batch file {}: {}", input.display(), error)))?))
+    };
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path).map_err(|error| SelectorError::Config(format!("Could not create {}: {}", path.display(), error)))?)),
+        None => Box::new(io::stdout().lock())
+    };
+    let write_error = |error : io::Error| SelectorError::Config(format!("Could not write the batch results: {}", error));
+
+    // Players, names and history are read once and shared by every request
+    let mut source = MemorySource::new(source::open_data_source(&args.source.options(), &args.database.options())?);
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|error| SelectorError::Config(format!("Could not read batch file {}: {}", input.display(), error)))?;
+        if line.trim().is_empty() {
+            continue;
         }
 
-        GameType::Sm5_14_Player => {
-
-            let postion_array = vec![0, 1, 2, 2, 2, 3, 4];
-            let mut final_vec: Vec<Vec<PlayerSlot>> = Vec::new();
-            for _team in 0..team_count {
-
-                let mut player_vec: Vec<PlayerSlot> = Vec::new();
-                for player in &postion_array {
-                    player_vec.push(
-                        PlayerSlot { 
-                            position: *player,
-                            position_pretty: String::from(""),
-                            player_id: -1,
-                            smvp: -1.0,
-                            player_name : "Unfilled slot! Report to Metalface!".to_string()
-                        }
-                    );
-                }
-                final_vec.push(player_vec);
-            }
+        let line_args = request::parse_request(&line).map(|input| {
+            let mut line_args = args.clone();
+            apply_request(&mut line_args, input);
+            line_args
+        });
+        let result = line_args.and_then(|line_args| {
+            let (selection, model) = make_selection(&line_args, &mut source)?;
+            let seed = selection.seed;
+            Ok(batch_selection_line(index + 1, selection.lineups, line_args.candidates > 1, seed, &model, line_args.lopsided_threshold))
+        });
+
+        let json = result.unwrap_or_else(|error| batch_error_line(index + 1, &error));
+        writeln!(writer, "{}", json).map_err(write_error)?;
+    }
 
-            return final_vec;
-        }
+    return writer.flush().map_err(write_error);
+}
 
-        GameType::Sm5_QueenBee => {
-            let postion_array = vec![2, 2, 3, 4];
-            let mut final_vec: Vec<Vec<PlayerSlot>> = Vec::new();
-            for _team in 0..team_count {
-
-                let mut player_vec: Vec<PlayerSlot> = Vec::new();
-                for player in &postion_array {
-                    player_vec.push(
-                        PlayerSlot { 
-                            position: *player,
-                            position_pretty: String::from(""),
-                            player_id: -1,
-                            smvp: -1.0,
-                            player_name : "Unfilled slot! Report to Metalface!".to_string()
-                        }
-                    );
-                }
-                final_vec.push(player_vec);
-            }
-            return final_vec;
-        }
+/// Load the players' stats and make teams out of them
+fn make_selection(args : &SelectorArgs, source : &mut dyn DataSource) -> Result<(Selection, PredictionModel), SelectorError> {
+    let settings = SelectionSettings {
+        game_type: args.game_type.clone(),
+        team_count: args.team_count,
+        algorithm: args.algorithm.clone(),
+        player_ids: args.player.clone(),
+        player_names: args.player_name.clone(),
+        new_players: args.new_player.clone(),
+        modifier_position: args.modifier_position.clone(),
+        modifier_team: args.modifier_team.clone(),
+        stats: args.stats.parameters(args.mvp_calculation_mode.clone())?,
+        model: args.model.clone(),
+        seed: args.seed,
+        candidates: args.candidates as usize,
+        time_budget: Duration::from_millis(args.time_budget_ms),
+        refine_iterations: args.refine.then_some(args.refine_iterations)
+    };
+    let (selection, model) = select_players(source, &settings)?;
+
+    if selection.search_timed_out {
+        eprintln!("Warning: the optimal search ran out of time budget, using the fairest lineups found. Raise --time-budget-ms to search longer.");
     }
-}
\ No newline at end of file
+
+    return Ok((selection, model));
+}
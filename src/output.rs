@@ -1,29 +1,210 @@
-use crate::{stats::set_position_from_index, PlayerSlot};
+use std::{collections::BTreeMap, fmt::Write};
 
-pub fn output_game_as_json(mut game: Vec<Vec<PlayerSlot>>) {
+use serde::Serialize;
 
-    for team_index in 0..game.len() {
-        for player_index in 0..game[0].len() {
-            game[team_index][player_index].position_pretty = set_position_from_index(game[team_index][player_index].position);
+use crate::{error::SelectorError, prediction::{predict, Prediction, PredictionModel}, selection::{lineup_score, slot_mismatch, team_imbalance}, stats::set_position_from_index, PlayerSlot, RankedLineup, Refinement};
+
+#[derive(Serialize)]
+struct GameOutput {
+    seed: u64,
+    balance: BalanceSummary,
+    /// Left out with one team
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prediction: Option<Prediction>,
+    /// Only there when --refine is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refinement: Option<Refinement>,
+    teams: Vec<Vec<PlayerSlot>>
+}
+
+#[derive(Serialize)]
+struct CandidateOutput {
+    rank: usize,
+    balance: BalanceSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prediction: Option<Prediction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refinement: Option<Refinement>,
+    teams: Vec<Vec<PlayerSlot>>
+}
+
+/// How fair a lineup is, so the frontend doesn't have to work it out again
+#[derive(Serialize)]
+struct BalanceSummary {
+    score: f64,
+    team_imbalance: f64,
+    slot_mismatch: f64,
+    teams: Vec<TeamSummary>
+}
+
+#[derive(Serialize)]
+struct TeamSummary {
+    total_smvp: f64,
+    average_smvp: f64,
+    position_smvp: BTreeMap<String, f64>
+}
+
+#[derive(Serialize)]
+struct BatchLine<T: Serialize> {
+    line: usize,
+    #[serde(flatten)]
+    result: T
+}
+
+#[derive(Serialize)]
+struct ErrorOutput {
+    error: ErrorDetails
+}
+
+#[derive(Serialize)]
+struct ErrorDetails {
+    kind: &'static str,
+    message: String,
+    exit_code: i32
+}
+
+#[derive(Serialize)]
+struct CandidatesOutput {
+    seed: u64,
+    candidates: Vec<CandidateOutput>
+}
+
+/// The lineups as JSON: every candidate when ranked, otherwise just the best lineup
+pub fn selection_as_json(mut candidates: Vec<RankedLineup>, ranked: bool, seed: u64, model: &PredictionModel, lopsided_threshold: f64) -> String {
+    if ranked {
+        return serde_json::to_string_pretty(&candidates_output(candidates, seed, model, lopsided_threshold)).unwrap();
+    }
+    return serde_json::to_string_pretty(&game_output(candidates.remove(0), seed, model, lopsided_threshold)).unwrap();
+}
+
+/// The lineups as lines of text for people to read: every candidate when ranked, otherwise just the best lineup
+pub fn selection_as_text(mut candidates: Vec<RankedLineup>, ranked: bool, seed: u64, model: &PredictionModel, lopsided_threshold: f64) -> String {
+    let mut text = format!("Seed: {}\n", seed);
+
+    if !ranked {
+        candidates.truncate(1);
+    }
+    for (index, lineup) in candidates.into_iter().enumerate() {
+        let game = lineup.teams;
+        if ranked {
+            writeln!(text, "Candidate {} - Score: {:.2}", index + 1, lineup.score).unwrap();
         }
+        write_teams(&mut text, &game);
+        write_balance(&mut text, &game);
+        write_refinement(&mut text, lineup.refinement);
+        write_prediction(&mut text, &game, model, lopsided_threshold);
     }
 
-    print!("{}",serde_json::to_string_pretty(&game).unwrap());
+    return text;
+}
+
+/// An error as JSON, so the website reads it from the same place as a selection
+pub fn error_as_json(error: &SelectorError) -> String {
+    return serde_json::to_string_pretty(&error_output(error)).unwrap();
 }
 
-pub fn output_game_text(mut game: Vec<Vec<PlayerSlot>>) {
+/// One line of batch output for a selection, the same as selection_as_json with the request's line number first
+pub fn batch_selection_line(line: usize, mut candidates: Vec<RankedLineup>, ranked: bool, seed: u64, model: &PredictionModel, lopsided_threshold: f64) -> String {
+    if ranked {
+        return serde_json::to_string(&BatchLine { line, result: candidates_output(candidates, seed, model, lopsided_threshold) }).unwrap();
+    }
+    return serde_json::to_string(&BatchLine { line, result: game_output(candidates.remove(0), seed, model, lopsided_threshold) }).unwrap();
+}
 
-    for team_index in 0..game.len() {
-        for player_index in 0..game[0].len() {
-            game[team_index][player_index].position_pretty = set_position_from_index(game[team_index][player_index].position);
+/// One line of batch output for a request that failed
+pub fn batch_error_line(line: usize, error: &SelectorError) -> String {
+    return serde_json::to_string(&BatchLine { line, result: error_output(error) }).unwrap();
+}
+
+fn game_output(lineup: RankedLineup, seed: u64, model: &PredictionModel, lopsided_threshold: f64) -> GameOutput {
+    let game = lineup.teams;
+
+    return GameOutput {
+        seed,
+        balance: summarize_balance(&game),
+        prediction: predict(&game, model, lopsided_threshold),
+        refinement: lineup.refinement,
+        teams: game
+    };
+}
+
+fn candidates_output(candidates: Vec<RankedLineup>, seed: u64, model: &PredictionModel, lopsided_threshold: f64) -> CandidatesOutput {
+
+    let mut output = CandidatesOutput { seed, candidates: Vec::new() };
+
+    for (index, lineup) in candidates.into_iter().enumerate() {
+        let game = lineup.teams;
+        output.candidates.push(CandidateOutput {
+            rank: index + 1,
+            balance: summarize_balance(&game),
+            prediction: predict(&game, model, lopsided_threshold),
+            refinement: lineup.refinement,
+            teams: game
+        });
+    }
+
+    return output;
+}
+
+fn error_output(error: &SelectorError) -> ErrorOutput {
+    return ErrorOutput {
+        error: ErrorDetails { kind: error.kind(), message: error.to_string(), exit_code: error.exit_code() }
+    };
+}
+
+fn write_teams(text: &mut String, game: &[Vec<PlayerSlot>]) {
+    for (team_index, team) in game.iter().enumerate() {
+        writeln!(text, "Team {}", team_index).unwrap();
+        for player in team {
+            writeln!(text, "Player: {}, Position: {}, SMVP: {}", player.player_name, player.position_pretty, player.smvp).unwrap();
         }
     }
+}
 
-    for team_index in 0..game.len() {
-        print!{"Team {}\n", team_index};
-        for player_index in 0..game[0].len() {
-            let player = game[team_index][player_index].clone();
-            print!("Player: {}, Position: {}, SMVP: {}\n", player.player_name, player.position_pretty, player.smvp);
+fn summarize_balance(game: &[Vec<PlayerSlot>]) -> BalanceSummary {
+    let teams = game.iter().map(|team| {
+        let total_smvp: f64 = team.iter().map(|slot| slot.smvp).sum();
+        let mut position_smvp = BTreeMap::new();
+        for slot in team {
+            *position_smvp.entry(set_position_from_index(slot.position)).or_insert(0.0) += slot.smvp;
+        }
+        TeamSummary {
+            total_smvp,
+            average_smvp: total_smvp / team.len() as f64,
+            position_smvp
         }
+    }).collect();
+
+    BalanceSummary {
+        score: lineup_score(game),
+        team_imbalance: team_imbalance(game),
+        slot_mismatch: slot_mismatch(game),
+        teams
+    }
+}
+
+fn write_balance(text: &mut String, game: &[Vec<PlayerSlot>]) {
+    let balance = summarize_balance(game);
+    for (team_index, team) in balance.teams.iter().enumerate() {
+        let positions: Vec<String> = team.position_smvp.iter().map(|(position, smvp)| format!("{}: {:.2}", position, smvp)).collect();
+        writeln!(text, "Team {} - Total SMVP: {:.2}, Average SMVP: {:.2}, {}", team_index, team.total_smvp, team.average_smvp, positions.join(", ")).unwrap();
+    }
+    writeln!(text, "Team imbalance: {:.2}, Slot mismatch: {:.2}, Score: {:.2}", balance.team_imbalance, balance.slot_mismatch, balance.score).unwrap();
+}
+
+fn write_refinement(text: &mut String, refinement: Option<Refinement>) {
+    if let Some(refinement) = refinement {
+        writeln!(text, "Refinement: team SMVP imbalance {:.2} -> {:.2}", refinement.imbalance_before, refinement.imbalance_after).unwrap();
+    }
+}
+
+fn write_prediction(text: &mut String, game: &[Vec<PlayerSlot>], model: &PredictionModel, lopsided_threshold: f64) {
+    let Some(prediction) = predict(game, model, lopsided_threshold) else {
+        return;
+    };
+    let chances: Vec<String> = prediction.win_probability.iter().enumerate().map(|(team_index, probability)| format!("Team {}: {:.1}%", team_index, probability * 100.0)).collect();
+    writeln!(text, "Predicted win chance - {}", chances.join(", ")).unwrap();
+    if prediction.lopsided {
+        writeln!(text, "Warning: This game looks lopsided.").unwrap();
     }
 }
\ No newline at end of file
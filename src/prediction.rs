@@ -0,0 +1,75 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::SelectorError, PlayerSlot};
+
+/// Logistic model over team strength. A team's strength is the sum of its players' smvp at their positions,
+/// and each team's chance to win is the softmax of `scale` times the strengths (the logistic function for two teams).
+/// The smvp weights live here too, so a trained model changes how the selector rates players as well.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PredictionModel {
+    /// Weight on hit_diff * mvp for each position, by position index
+    pub position_weights: [f64; 5],
+    /// Weight on games played at the position
+    pub games_weight: f64,
+    /// Turns a difference in team smvp into log odds of winning
+    pub scale: f64
+}
+
+impl Default for PredictionModel {
+    /// The original hand made smvp formula. Scale is picked so a difference of about one decent player (10 smvp) is roughly a 73% favourite
+    fn default() -> Self {
+        PredictionModel {
+            position_weights: [1.0; 5],
+            games_weight: 0.01,
+            scale: 0.1
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Prediction {
+    pub win_probability: Vec<f64>,
+    pub lopsided: bool
+}
+
+/// Predict each team's chance of winning a lineup. The game is lopsided when any team's chance reaches `lopsided_threshold`.
+/// A single team has nobody to beat, so there is nothing to predict
+pub fn predict(game : &[Vec<PlayerSlot>], model : &PredictionModel, lopsided_threshold : f64) -> Option<Prediction> {
+
+    if game.len() < 2 {
+        return None;
+    }
+
+    let strengths: Vec<f64> = game.iter().map(|team| team_strength(team)).collect();
+
+    let win_probability = win_probability(&strengths, model);
+    let lopsided = win_probability.iter().any(|probability| *probability >= lopsided_threshold);
+
+    Some(Prediction { win_probability, lopsided })
+}
+
+/// Each team's chance to win given their total smvp
+pub fn win_probability(strengths : &[f64], model : &PredictionModel) -> Vec<f64> {
+
+    // Subtract the strongest team before exponentiating so nothing overflows
+    let strongest = strengths.iter().cloned().fold(f64::MIN, f64::max);
+    let weights: Vec<f64> = strengths.iter().map(|strength| (model.scale * (strength - strongest)).exp()).collect();
+    let total: f64 = weights.iter().sum();
+
+    weights.iter().map(|weight| weight / total).collect()
+}
+
+pub fn load_model(path : &Path) -> Result<PredictionModel, SelectorError> {
+    let contents = fs::read_to_string(path).map_err(|error| SelectorError::Config(format!("Could not read model file {}: {}", path.display(), error)))?;
+    serde_json::from_str(&contents).map_err(|_| SelectorError::Config(format!("Model file {} is not a valid model, train a new one with the train command.", path.display())))
+}
+
+pub fn save_model(model : &PredictionModel, path : &Path) -> Result<(), SelectorError> {
+    fs::write(path, serde_json::to_string_pretty(model).unwrap()).map_err(|error| SelectorError::Config(format!("Could not write model file {}: {}", path.display(), error)))
+}
+
+fn team_strength(team : &[PlayerSlot]) -> f64 {
+    team.iter().map(|slot| slot.smvp).sum()
+}
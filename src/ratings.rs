@@ -0,0 +1,233 @@
+use std::{collections::HashMap, f64::consts::PI};
+
+use crate::{history::{split_teams, HistoricalGame, HistoricalTeam}, stats::position_index_from_name, PlayerStats, PositionStats};
+
+const STARTING_RATING: f64 = 1500.0;
+const STARTING_DEVIATION: f64 = 350.0;
+
+/// Stops regular players' ratings from settling so hard they can't move any more
+const MINIMUM_DEVIATION: f64 = 50.0;
+
+/// Glicko's q, ln(10) / 400
+const Q: f64 = std::f64::consts::LN_10 / 400.0;
+
+/// A Glicko rating for one player at one position
+#[derive(Clone, Copy)]
+pub struct PositionRating {
+    pub rating: f64,
+    pub deviation: f64,
+    pub games: u64
+}
+
+impl Default for PositionRating {
+    fn default() -> Self {
+        PositionRating { rating: STARTING_RATING, deviation: STARTING_DEVIATION, games: 0 }
+    }
+}
+
+impl PositionRating {
+    /// The rating we're fairly sure the player is at least as good as
+    fn conservative(&self) -> f64 {
+        self.rating - self.deviation
+    }
+}
+
+/// Per position Glicko ratings, updated game by game. Each player is rated as if they played one match against
+/// the average of the opposing team's ratings at their positions, and the result is their team's result.
+#[derive(Default)]
+pub struct RatingTracker {
+    ratings: HashMap<(u64, u64), PositionRating>
+}
+
+impl RatingTracker {
+    pub fn rating(&self, player_id : u64, position : u64) -> PositionRating {
+        self.ratings.get(&(player_id, position)).copied().unwrap_or_default()
+    }
+
+    /// Update everyone in a finished game. Draws and games without exactly two teams are skipped
+    pub fn record(&mut self, game : &HistoricalGame) {
+        let Some([first, second]) = split_teams(game) else { return };
+        if first.won == second.won {
+            return;
+        }
+
+        // Everyone is rated against the ratings from before this game
+        let first_opponent = self.team_average(&first);
+        let second_opponent = self.team_average(&second);
+
+        let mut updates = Vec::new();
+        for (team, opponent) in [(&first, second_opponent), (&second, first_opponent)] {
+            for card in &team.scorecards {
+                let Some(position) = position_index_from_name(&card.position) else { continue };
+                let updated = update_rating(self.rating(card.player_id, position), opponent, team.won);
+                updates.push(((card.player_id, position), updated));
+            }
+        }
+
+        self.ratings.extend(updates);
+    }
+
+    /// Express a player's ratings as position stats so the rest of the selector can use them. The conservative rating
+    /// goes on the MVP scale (1500 is 7.0, each 100 points is 1.0) and hit diff is left at 1.0
+    pub fn player_stats(&self, player_id : u64, player_name : String) -> PlayerStats {
+        let position_stats = |position : u64| {
+            let rating = self.rating(player_id, position);
+            PositionStats {
+                mvp: 7.0 + (rating.conservative() - STARTING_RATING) / 100.0,
+                hit_diff: 1.0,
+                games_played: rating.games
+            }
+        };
+
+        PlayerStats {
+            player_id,
+            player_name,
+            commander_stats: position_stats(0),
+            heavy_stats: position_stats(1),
+            scout_stats: position_stats(2),
+            ammo_stats: position_stats(3),
+            medic_stats: position_stats(4),
+        }
+    }
+
+    /// The team's average rating and deviation, as the single opponent the other team played
+    fn team_average(&self, team : &HistoricalTeam) -> PositionRating {
+        let ratings: Vec<PositionRating> = team.scorecards.iter()
+            .filter_map(|card| position_index_from_name(&card.position).map(|position| self.rating(card.player_id, position)))
+            .collect();
+
+        if ratings.is_empty() {
+            return PositionRating::default();
+        }
+
+        let count = ratings.len() as f64;
+        PositionRating {
+            rating: ratings.iter().map(|r| r.rating).sum::<f64>() / count,
+            deviation: (ratings.iter().map(|r| r.deviation.powi(2)).sum::<f64>() / count).sqrt(),
+            games: 0
+        }
+    }
+}
+
+/// One Glicko rating period with a single opponent
+fn update_rating(player : PositionRating, opponent : PositionRating, won : bool) -> PositionRating {
+    let g = 1.0 / (1.0 + 3.0 * Q.powi(2) * opponent.deviation.powi(2) / PI.powi(2)).sqrt();
+    let expected = 1.0 / (1.0 + 10f64.powf(-g * (player.rating - opponent.rating) / 400.0));
+    let d_squared = 1.0 / (Q.powi(2) * g.powi(2) * expected * (1.0 - expected));
+
+    let precision = 1.0 / player.deviation.powi(2) + 1.0 / d_squared;
+    let score = if won { 1.0 } else { 0.0 };
+
+    PositionRating {
+        rating: player.rating + Q / precision * g * (score - expected),
+        deviation: (1.0 / precision).sqrt().max(MINIMUM_DEVIATION),
+        games: player.games + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scorecard;
+
+    /// Players 1 (Commander) and 2 (Heavy Weapons) on red against 3 and 4 on green
+    fn game(game_id : i64, red_won : bool, green_won : bool) -> HistoricalGame {
+        let scorecards = vec![
+            scorecard(1, "Commander", 10.0, game_id, "red", red_won),
+            scorecard(2, "Heavy Weapons", 10.0, game_id, "red", red_won),
+            scorecard(3, "Commander", 10.0, game_id, "green", green_won),
+            scorecard(4, "Heavy Weapons", 10.0, game_id, "green", green_won)
+        ];
+        return HistoricalGame { game_id, scorecards };
+    }
+
+    #[test]
+    fn winners_rise_and_losers_fall() {
+        let mut tracker = RatingTracker::default();
+        tracker.record(&game(1, true, false));
+
+        for (player_id, position) in [(1, 0), (2, 1)] {
+            assert!(tracker.rating(player_id, position).rating > STARTING_RATING);
+            assert_eq!(tracker.rating(player_id, position).games, 1);
+        }
+        for (player_id, position) in [(3, 0), (4, 1)] {
+            assert!(tracker.rating(player_id, position).rating < STARTING_RATING);
+        }
+        // Only the position played is rated
+        assert_eq!(tracker.rating(1, 1).games, 0);
+    }
+
+    #[test]
+    fn deviation_shrinks_down_to_the_minimum() {
+        let mut tracker = RatingTracker::default();
+        let mut deviation = STARTING_DEVIATION;
+        for game_id in 0..200 {
+            tracker.record(&game(game_id, game_id % 2 == 0, game_id % 2 == 1));
+            let rating = tracker.rating(1, 0);
+            assert!(rating.deviation <= deviation && rating.deviation >= MINIMUM_DEVIATION, "game {}", game_id);
+            deviation = rating.deviation;
+        }
+        assert!(tracker.rating(1, 0).deviation < STARTING_DEVIATION);
+        assert_eq!(deviation, MINIMUM_DEVIATION);
+    }
+
+    #[test]
+    fn draws_and_games_without_two_teams_are_skipped() {
+        let mut three_teams = game(3, true, false);
+        three_teams.scorecards.push(scorecard(5, "Scout", 10.0, 3, "blue", false));
+        let mut one_team = game(4, true, false);
+        one_team.scorecards.retain(|card| card.team == "red");
+
+        let mut tracker = RatingTracker::default();
+        for skipped in [game(1, true, true), game(2, false, false), three_teams, one_team] {
+            tracker.record(&skipped);
+        }
+        for player_id in 1..=5 {
+            for position in 0..5 {
+                assert_eq!(tracker.rating(player_id, position).games, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn opponents_are_the_team_average() {
+        let mut tracker = RatingTracker::default();
+        tracker.ratings.insert((3, 0), PositionRating { rating: 1600.0, deviation: 100.0, games: 5 });
+        tracker.ratings.insert((4, 1), PositionRating { rating: 1400.0, deviation: 200.0, games: 5 });
+
+        // Teams come back sorted by name
+        let game = game(1, true, false);
+        let [green, _] = split_teams(&game).unwrap();
+        let average = tracker.team_average(&green);
+        assert!((average.rating - 1500.0).abs() < 1e-9);
+        assert!((average.deviation - 25000f64.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn beating_a_stronger_team_gains_more() {
+        let mut against_strong = RatingTracker::default();
+        against_strong.ratings.insert((3, 0), PositionRating { rating: 1800.0, deviation: 100.0, games: 5 });
+        against_strong.ratings.insert((4, 1), PositionRating { rating: 1800.0, deviation: 100.0, games: 5 });
+        against_strong.record(&game(1, true, false));
+
+        let mut against_new = RatingTracker::default();
+        against_new.record(&game(1, true, false));
+
+        assert!(against_strong.rating(1, 0).rating > against_new.rating(1, 0).rating);
+    }
+
+    #[test]
+    fn conservative_ratings_go_on_the_mvp_scale() {
+        let mut tracker = RatingTracker::default();
+        tracker.ratings.insert((1, 2), PositionRating { rating: 1700.0, deviation: 100.0, games: 12 });
+
+        let stats = tracker.player_stats(1, String::from("p1"));
+        // 1700 - 100 is 100 points over 1500
+        assert!((stats.scout_stats.mvp - 8.0).abs() < 1e-9);
+        assert_eq!(stats.scout_stats.hit_diff, 1.0);
+        assert_eq!(stats.scout_stats.games_played, 12);
+        // Unrated positions start at 1500 - 350
+        assert!((stats.medic_stats.mvp - 3.5).abs() < 1e-9);
+        assert_eq!(stats.medic_stats.games_played, 0);
+    }
+}
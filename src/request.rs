@@ -0,0 +1,101 @@
+use std::{fs, io::{self, Read}, path::{Path, PathBuf}};
+
+use serde::{de::Error, Deserialize, Deserializer};
+
+use crate::{error::SelectorError, Algorithm, GameType, MVPCalculationMode, NGamesScope, NamedOption};
+
+/// A whole selection as one JSON document, so the website can send structured data instead of building a command line.
+/// Every field is optional. Lists are added to anything given on the command line, everything else replaces it.
+/// The schema is in the README
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelectionInput {
+    #[serde(deserialize_with = "named_option")]
+    pub game_type: Option<GameType>,
+    #[serde(deserialize_with = "named_option")]
+    pub algorithm: Option<Algorithm>,
+    #[serde(deserialize_with = "named_option")]
+    pub mvp_calculation_mode: Option<MVPCalculationMode>,
+    pub team_count: Option<u8>,
+    pub players: Vec<u64>,
+    pub player_names: Vec<String>,
+    pub new_players: Vec<String>,
+    pub position_locks: Vec<PositionLock>,
+    pub team_locks: Vec<TeamLock>,
+    pub options: SelectionOptions
+}
+
+/// Force a player to play a position, by position index
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PositionLock {
+    pub player_id: u64,
+    pub position: u64
+}
+
+/// Force a player onto a team, by team index
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TeamLock {
+    pub player_id: u64,
+    pub team: u64
+}
+
+/// The rest of the command line arguments, named the same with underscores
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelectionOptions {
+    pub n_games: Option<u64>,
+    #[serde(deserialize_with = "named_option")]
+    pub n_games_scope: Option<NGamesScope>,
+    pub shrinkage_strength: Option<f64>,
+    pub half_life_days: Option<f64>,
+    pub time_budget_ms: Option<u64>,
+    pub refine: Option<bool>,
+    pub refine_iterations: Option<u64>,
+    pub seed: Option<u64>,
+    pub candidates: Option<u64>,
+    pub model: Option<PathBuf>,
+    pub lopsided_threshold: Option<f64>
+}
+
+impl SelectionInput {
+    /// Position locks in the player_id, position pairs --modifier-position takes
+    pub fn modifier_position(&self) -> Vec<u64> {
+        return self.position_locks.iter().flat_map(|lock| [lock.player_id, lock.position]).collect();
+    }
+
+    /// Team locks in the player_id, team pairs --modifier-team takes
+    pub fn modifier_team(&self) -> Vec<u64> {
+        return self.team_locks.iter().flat_map(|lock| [lock.player_id, lock.team]).collect();
+    }
+}
+
+/// Read a selection request from a file, or stdin when the path is `-`
+pub fn read_request(path : &Path) -> Result<SelectionInput, SelectorError> {
+    let mut text = String::new();
+    if path == Path::new("-") {
+        io::stdin().read_to_string(&mut text).map_err(|error| SelectorError::Config(format!("Could not read the request from stdin: {}", error)))?;
+    } else {
+        text = fs::read_to_string(path).map_err(|error| SelectorError::Config(format!("Could not read request file {}: {}", path.display(), error)))?;
+    }
+
+    return parse_request(&text);
+}
+
+/// Parse a selection request. Values that parse but make no sense, like 0 teams, are rejected by select_teams
+pub fn parse_request(text : &str) -> Result<SelectionInput, SelectorError> {
+    return serde_json::from_str(text).map_err(|error| SelectorError::InvalidRequest(format!("The request is not valid: {}", error)));
+}
+
+/// Enums are written the same as on the command line, like "optimal-selection"
+fn named_option<'de, D, T>(deserializer : D) -> Result<Option<T>, D::Error> where D: Deserializer<'de>, T: NamedOption {
+    let Some(value) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+
+    return T::from_name(&value).map(Some).ok_or_else(|| {
+        let expected: Vec<&str> = T::ALL.iter().map(|option| option.name()).collect();
+        D::Error::custom(format!("unknown value {}, expected one of {}", value, expected.join(", ")))
+    });
+}
@@ -1,70 +1,137 @@
-use rand::{random_bool, rng, seq::IndexedRandom};
+use std::time::{Duration, Instant};
 
-use crate::{PlayerSlot, PlayerStats, PositionStats};
+use rand::{rngs::StdRng, seq::{IndexedRandom, SliceRandom}};
 
-pub fn random_random(mut game : Vec<Vec<PlayerSlot>>, mut players : Vec<PlayerStats>, _modifiers_position : &Option<Vec<u64>>, _modifiers_team : &Option<Vec<u64>>) -> Vec<Vec<PlayerSlot>> {
+use crate::{error::SelectorError, prediction::PredictionModel, stats::set_position_from_index, PlayerSlot, PlayerStats, PositionStats, RankedLineup, Refinement};
 
-    let mut rng = rng();
+/// Every team's slots in one selection
+pub type Lineup = Vec<Vec<PlayerSlot>>;
+
+pub fn random_random(game : Vec<Vec<PlayerSlot>>, players : Vec<PlayerStats>, modifiers_position : &Option<Vec<u64>>, modifiers_team : &Option<Vec<u64>>, model : &PredictionModel, rng : &mut StdRng) -> Result<Vec<Vec<PlayerSlot>>, SelectorError> {
+
+    let given = players.len();
+    let mut team_order: Vec<usize> = (0..game.len()).collect();
+    team_order.shuffle(rng);
+
+    let (mut game, mut players) = assign_modifiers(game, players, modifiers_position, modifiers_team, &team_order, model)?;
 
     for (team_index, team) in game.clone().iter().enumerate() {
         for (slot_index, slot) in team.clone().iter().enumerate() {
-            let player = players.choose(&mut rng).unwrap().clone();
+            // Locked players were placed already
+            if slot.player_id != -1 {
+                continue;
+            }
+
+            let player = players.choose(rng).ok_or_else(|| pool_ran_out(&game, given))?.clone();
             players.retain(|p| p.player_id != player.player_id);
 
             let player_position_stats = retrieve_stat_block_from_position(slot.position, player.clone());
 
-            map_player_to_slot(player, player_position_stats, &mut game[team_index][slot_index]);
+            map_player_to_slot(player, player_position_stats, &mut game[team_index][slot_index], model);
 
         }
     }
 
-    return game;
+    return Ok(game);
 
 }
 
-pub fn calculate_advanced(mut game : Vec<Vec<PlayerSlot>>, mut players : Vec<PlayerStats>, modifiers_position : &Option<Vec<u64>>, _modifiers_team : &Option<Vec<u64>>) -> Vec<Vec<PlayerSlot>> { 
+/// Deterministic snake draft. For every slot, rank the remaining players by their smvp at that slot's position
+/// and hand them out across the teams, reversing the team order each slot (A-B, B-A, A-B...).
+pub fn simple_selection(game : Vec<Vec<PlayerSlot>>, players : Vec<PlayerStats>, modifiers_position : &Option<Vec<u64>>, modifiers_team : &Option<Vec<u64>>, model : &PredictionModel) -> Result<Vec<Vec<PlayerSlot>>, SelectorError> {
+
+    let given = players.len();
+    let team_order: Vec<usize> = (0..game.len()).collect();
+    let (mut game, mut players) = assign_modifiers(game, players, modifiers_position, modifiers_team, &team_order, model)?;
+
+    let team_count = game.len();
+
+    for slot_index in 0..game[0].len() {
+        let position = game[0][slot_index].position;
+
+        // Best player first, ties broken by id so the draft never changes between runs
+        players.sort_by(|a, b| {
+            let a_smvp = calculate_smvp(retrieve_stat_block_from_position(position, a.clone()), position, model);
+            let b_smvp = calculate_smvp(retrieve_stat_block_from_position(position, b.clone()), position, model);
+            b_smvp.total_cmp(&a_smvp).then(a.player_id.cmp(&b.player_id))
+        });
+
+        let draft_order: Vec<usize> = if slot_index.is_multiple_of(2) {
+            (0..team_count).collect()
+        } else {
+            (0..team_count).rev().collect()
+        };
+
+        for team_index in draft_order {
+            // Locked players were placed already
+            if game[team_index][slot_index].player_id != -1 {
+                continue;
+            }
 
-    if let Some(modifiers) = modifiers_position {
-        (game, players) = assign_position_modifier_to_slot(game, players, modifiers);
+            if players.is_empty() {
+                return Err(pool_ran_out(&game, given));
+            }
+            let player = players.remove(0);
+            let player_position_stats = retrieve_stat_block_from_position(position, player.clone());
+            map_player_to_slot(player, player_position_stats, &mut game[team_index][slot_index], model);
+        }
     }
 
-    // Choose a random slot from a random team
-    // Assign a player to that
-    // Now look for the closest matching player from playerstats
-    // Assign that player to the other team
+    return Ok(game);
+
+}
+
+pub fn calculate_advanced(game : Vec<Vec<PlayerSlot>>, players : Vec<PlayerStats>, modifiers_position : &Option<Vec<u64>>, modifiers_team : &Option<Vec<u64>>, model : &PredictionModel, rng : &mut StdRng) -> Result<Vec<Vec<PlayerSlot>>, SelectorError> { 
+
+    let given = players.len();
+
+    // Shuffle which teams modified players lean towards so they don't always end up on team 0
+    let mut team_order: Vec<usize> = (0..game.len()).collect();
+    team_order.shuffle(rng);
 
-    let mut rng = rng();
+    let (mut game, mut players) = assign_modifiers(game, players, modifiers_position, modifiers_team, &team_order, model)?;
+
+    // Rotate which team seeds each slot
+    // Assign a random player to that slot, unless a modifier already filled the slot on some team
+    // Now look for the closest matching players from playerstats for every other team
+    // The strongest of those go to the teams that are furthest behind
+
+    let team_count = game.len();
 
     // Run once per slot on one team
-    for slot_index in 0..game.clone().first().unwrap().len() {
+    for slot_index in 0..game[0].len() {
 
-        let player;
         let position = game[0][slot_index].position;
+        let seed_team = slot_index % team_count;
         let smvp;
-                        
-        let mut modifier_used = false;
 
-        // Skip assigning a player if one is already assigned from modifiers
+        // Skip assigning a player if one is already assigned from modifiers - match against them instead
 
-        if game[0][slot_index].player_id != -1 {
+        if let Some(locked_team) = game.iter().position(|team| team[slot_index].player_id != -1) {
             // Find that player - grab their stats
-            smvp = game[0][slot_index].smvp;
-            modifier_used = true;
+            smvp = game[locked_team][slot_index].smvp;
         } else {
             // Unassigned slot, randomly choose a player from the pool
-            player = players.choose(&mut rng).unwrap().clone();
+            let player = players.choose(rng).ok_or_else(|| pool_ran_out(&game, given))?.clone();
             players.retain(|p| p.player_id != player.player_id);
-            game[0][slot_index].player_id = player.player_id.try_into().unwrap();
 
             let stats = retrieve_stat_block_from_position(position, player.clone());
-            smvp = calculate_smvp(stats);
+            smvp = calculate_smvp(stats, position, model);
 
-            game[0][slot_index].smvp = smvp;
-            game[0][slot_index].player_name = player.player_name.clone();
+            map_player_to_slot(player, stats, &mut game[seed_team][slot_index], model);
         }
 
-        // Match a player for each opposing team (usually just 1)
-        for team_match in 1..game.len() {
+        // Teams still missing a player for this slot, furthest behind first
+        let team_totals: Vec<f64> = game.iter()
+            .map(|team| team.iter().filter(|slot| slot.player_id != -1).map(|slot| slot.smvp).sum())
+            .collect();
+        let mut open_teams: Vec<usize> = (0..team_count).filter(|team_index| game[*team_index][slot_index].player_id == -1).collect();
+        open_teams.sort_by(|a, b| team_totals[*a].total_cmp(&team_totals[*b]));
+
+        // Match a player for each open team (usually just 1)
+        let mut matched_players: Vec<(PlayerStats, f64)> = Vec::new();
+
+        for _ in 0..open_teams.len() {
             // Match the next closest player's position stats
 
             // At the end of this next for loop, should have the closest player in mvp
@@ -72,13 +139,11 @@ pub fn calculate_advanced(mut game : Vec<Vec<PlayerSlot>>, mut players : Vec<Pla
             let mut diff_index: usize = usize::MAX;
 
             let mut other_smvp_chosen: f64 = 0.0;
-            let mut other_name_chosen = String::from("");
 
 
             for (index, other_player) in players.iter().enumerate() {
                 let p_stats = retrieve_stat_block_from_position(position, other_player.clone());
-                let other_smvp = calculate_smvp(p_stats);
-                let other_name = other_player.player_name.clone();
+                let other_smvp = calculate_smvp(p_stats, position, model);
                 let diff = (smvp - other_smvp).abs();
 
 
@@ -86,99 +151,858 @@ pub fn calculate_advanced(mut game : Vec<Vec<PlayerSlot>>, mut players : Vec<Pla
                     min_diff = diff;
                     diff_index = index;
                     other_smvp_chosen = other_smvp;
-                    other_name_chosen = other_name;
                 }
             }
 
-            let matched_player_wrapped = players.get(diff_index);
-            let matched_player;
-            match matched_player_wrapped {
-                Some(player) => {matched_player = player.clone()}
-                None => {
-                    eprintln!("Players:");
-                    for player in players {
-                        eprintln!("Player Name: {}", player.player_name)
+            let Some(matched_player) = players.get(diff_index).cloned() else {
+                return Err(no_match(&game, &players, position, smvp, model, given));
+            };
+            players.retain(|p| p.player_id != matched_player.player_id);
+
+            matched_players.push((matched_player, other_smvp_chosen));
+        }
+
+        // Strongest match goes to the team furthest behind
+        matched_players.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        for (team_index, (player, _)) in open_teams.into_iter().zip(matched_players) {
+            let stats = retrieve_stat_block_from_position(position, player.clone());
+            map_player_to_slot(player, stats, &mut game[team_index][slot_index], model);
+        }
+    
+    }
+
+    return Ok(game);
+
+}
+
+/// Search every assignment of players to team slots for the fairest lineups by `lineup_score`, pruning branches that can't beat the best found.
+/// Returns up to `keep` distinct lineups, best first, and whether the time budget ran out before the search finished. The snake draft
+/// is used as the starting lineup, so if the time budget runs out the best result is never worse than simple selection.
+pub fn optimal_selection(game : Vec<Vec<PlayerSlot>>, players : Vec<PlayerStats>, modifiers_position : &Option<Vec<u64>>, modifiers_team : &Option<Vec<u64>>, model : &PredictionModel, time_budget : Duration, keep : usize) -> Result<(Vec<Lineup>, bool), SelectorError> {
+
+    // This also checks the modifiers for conflicts before searching
+    let incumbent = simple_selection(game.clone(), players.clone(), modifiers_position, modifiers_team, model)?;
+    let incumbent_assignment: Vec<Vec<usize>> = incumbent.iter()
+        .map(|team| team.iter().map(|slot| players.iter().position(|player| player.player_id as i64 == slot.player_id).ok_or_else(|| not_in_pool(slot))).collect())
+        .collect::<Result<_, _>>()?;
+
+    let mut search = LineupSearch::new(&game, &players, modifiers_position, modifiers_team, model, Instant::now() + time_budget, keep);
+    search.offer(lineup_score(&incumbent), incumbent_assignment);
+    search.search(0);
+
+    let mut lineups = Vec::new();
+    for (_, assignment) in &search.best {
+        let mut lineup = game.clone();
+        for (team_index, team) in assignment.iter().enumerate() {
+            for (slot_index, player_index) in team.iter().enumerate() {
+                let player = players[*player_index].clone();
+                let player_position_stats = retrieve_stat_block_from_position(lineup[team_index][slot_index].position, player.clone());
+                map_player_to_slot(player, player_position_stats, &mut lineup[team_index][slot_index], model);
+            }
+        }
+        lineups.push(lineup);
+    }
+
+    return Ok((lineups, search.timed_out));
+}
+
+/// State for the branch and bound search in optimal_selection. Slots are filled row by row (slot 0 on every team, then slot 1...)
+/// so per-slot mismatch is known as early as possible.
+struct LineupSearch {
+    positions: Vec<u64>,
+    team_count: usize,
+    order: Vec<(usize, usize)>,
+    smvp: Vec<[f64; 5]>,
+    /// Player indices by smvp at each position, lowest first
+    by_smvp: [Vec<usize>; 5],
+    position_lock: Vec<Option<u64>>,
+    team_lock: Vec<Option<usize>>,
+    teams_interchangeable: bool,
+    assignment: Vec<Vec<Option<usize>>>,
+    used: Vec<bool>,
+    team_totals: Vec<f64>,
+    keep: usize,
+    best: Vec<(f64, Vec<Vec<usize>>)>,
+    deadline: Instant,
+    nodes: u64,
+    timed_out: bool
+}
+
+impl LineupSearch {
+    fn new(game : &[Vec<PlayerSlot>], players : &[PlayerStats], modifiers_position : &Option<Vec<u64>>, modifiers_team : &Option<Vec<u64>>, model : &PredictionModel, deadline : Instant, keep : usize) -> LineupSearch {
+        let positions: Vec<u64> = game[0].iter().map(|slot| slot.position).collect();
+        let team_count = game.len();
+
+        let mut order = Vec::new();
+        for slot_index in 0..positions.len() {
+            for team_index in 0..team_count {
+                order.push((team_index, slot_index));
+            }
+        }
+
+        let smvp = players.iter().map(|player| {
+            let mut by_position = [0.0; 5];
+            for (position, value) in by_position.iter_mut().enumerate() {
+                *value = calculate_smvp(retrieve_stat_block_from_position(position as u64, player.clone()), position as u64, model);
+            }
+            by_position
+        }).collect::<Vec<[f64; 5]>>();
+        let by_smvp = std::array::from_fn(|position| {
+            let mut order: Vec<usize> = (0..players.len()).collect();
+            order.sort_by(|a, b| smvp[*a][position].total_cmp(&smvp[*b][position]));
+            order
+        });
+
+        let position_locks = pair_modifiers(modifiers_position);
+        let team_locks = pair_modifiers(modifiers_team);
+        let position_lock = players.iter()
+            .map(|player| position_locks.iter().find(|(id, _)| *id == player.player_id).map(|(_, position)| *position))
+            .collect();
+        let team_lock: Vec<Option<usize>> = players.iter()
+            .map(|player| team_locks.iter().find(|(id, _)| *id == player.player_id).map(|(_, team)| *team as usize))
+            .collect();
+
+        LineupSearch {
+            team_count,
+            order,
+            smvp,
+            by_smvp,
+            position_lock,
+            teams_interchangeable: team_lock.iter().all(|lock| lock.is_none()),
+            team_lock,
+            assignment: vec![vec![None; positions.len()]; team_count],
+            used: vec![false; players.len()],
+            team_totals: vec![0.0; team_count],
+            positions,
+            keep: keep.max(1),
+            best: Vec::new(),
+            deadline,
+            nodes: 0,
+            timed_out: false
+        }
+    }
+
+    fn search(&mut self, depth : usize) {
+        if self.timed_out {
+            return;
+        }
+
+        self.nodes += 1;
+        if self.nodes.is_multiple_of(1024) && Instant::now() > self.deadline {
+            self.timed_out = true;
+            return;
+        }
+
+        let unplaced_locked = (0..self.used.len()).filter(|p| !self.used[*p] && self.is_locked(*p)).count();
+        let slots_left = self.order.len() - depth;
+
+        if slots_left == 0 {
+            let cost = self.lower_bound();
+            if unplaced_locked == 0 {
+                let assignment = self.assignment.iter().map(|team| team.iter().map(|p| p.unwrap()).collect()).collect();
+                self.offer(cost, assignment);
+            }
+            return;
+        }
+
+        if unplaced_locked > slots_left || self.lower_bound() >= self.cutoff() - 1e-9 {
+            return;
+        }
+
+        let (team_index, slot_index) = self.order[depth];
+        let position = self.positions[slot_index];
+
+        let mut candidates: Vec<usize> = (0..self.used.len()).filter(|p| {
+            let p = *p;
+            if self.used[p] || (unplaced_locked == slots_left && !self.is_locked(p)) {
+                return false;
+            }
+            if self.position_lock[p].is_some_and(|locked| locked != position) || self.team_lock[p].is_some_and(|locked| locked != team_index) {
+                return false;
+            }
+            // Swapping two same position slots on every team at once gives the same score, so only one order is tried on
+            // the first team. Not when teams are interchangeable and it would move who is in slot 0, which orders the teams
+            let earlier = (0..slot_index).rev().find(|s| self.positions[*s] == position);
+            let rows_interchangeable = team_index == 0 && !(self.teams_interchangeable && position == self.positions[0]);
+            if rows_interchangeable && earlier.is_some_and(|earlier| self.assignment[team_index][earlier].is_some_and(|other| p < other)) {
+                return false;
+            }
+            // Without team locks the teams themselves are interchangeable too
+            if self.teams_interchangeable && slot_index == 0 && team_index > 0 && self.assignment[team_index - 1][0].is_some_and(|other| p < other) {
+                return false;
+            }
+            true
+        }).collect();
+
+        // Try the closest match to the rest of the slot first so good lineups are found early
+        let anchor = (0..self.team_count).find_map(|t| self.assignment[t][slot_index]).map(|p| self.smvp[p][position as usize]);
+        candidates.sort_by(|a, b| {
+            let a_smvp = self.smvp[*a][position as usize];
+            let b_smvp = self.smvp[*b][position as usize];
+            match anchor {
+                Some(anchor) => (a_smvp - anchor).abs().total_cmp(&(b_smvp - anchor).abs()),
+                None => b_smvp.total_cmp(&a_smvp)
+            }
+        });
+
+        for p in candidates {
+            let value = self.smvp[p][position as usize];
+            self.used[p] = true;
+            self.assignment[team_index][slot_index] = Some(p);
+            self.team_totals[team_index] += value;
+
+            self.search(depth + 1);
+
+            self.team_totals[team_index] -= value;
+            self.assignment[team_index][slot_index] = None;
+            self.used[p] = false;
+        }
+    }
+
+    /// Lineups must score below this to be kept
+    fn cutoff(&self) -> f64 {
+        if self.best.len() < self.keep {
+            f64::MAX
+        } else {
+            self.best.last().map_or(f64::MAX, |(cost, _)| *cost)
+        }
+    }
+
+    /// Keep a finished lineup if it is among the best found and isn't one we already have
+    fn offer(&mut self, cost : f64, assignment : Vec<Vec<usize>>) {
+        if cost >= self.cutoff() - 1e-9 {
+            return;
+        }
+
+        // The same lineup with its same position slots paired up better replaces the one kept
+        let key = self.lineup_key(&assignment);
+        if let Some(repeat) = self.best.iter().position(|(_, kept)| self.lineup_key(kept) == key) {
+            if cost >= self.best[repeat].0 - 1e-9 {
+                return;
+            }
+            self.best.remove(repeat);
+        }
+
+        let insert_at = self.best.iter().position(|(kept_cost, _)| cost < *kept_cost).unwrap_or(self.best.len());
+        self.best.insert(insert_at, (cost, assignment));
+        self.best.truncate(self.keep);
+    }
+
+    /// Who plays what on each team, ignoring team order and the order of same position slots
+    fn lineup_key(&self, assignment : &[Vec<usize>]) -> Vec<Vec<(u64, usize)>> {
+        let mut key: Vec<Vec<(u64, usize)>> = assignment.iter()
+            .map(|team| {
+                let mut team: Vec<(u64, usize)> = team.iter().enumerate().map(|(slot_index, p)| (self.positions[slot_index], *p)).collect();
+                team.sort();
+                team
+            })
+            .collect();
+        if self.teams_interchangeable {
+            key.sort();
+        }
+        key
+    }
+
+    fn is_locked(&self, p : usize) -> bool {
+        self.position_lock[p].is_some() || self.team_lock[p].is_some()
+    }
+
+    /// Whether an unplaced player could fill an open slot without breaking their locks
+    fn can_take(&self, p : usize, team_index : usize, position : u64) -> bool {
+        !self.used[p] && self.position_lock[p].is_none_or(|locked| locked == position) && self.team_lock[p].is_none_or(|locked| locked == team_index)
+    }
+
+    /// The lowest `lineup_score` any completion of the current partial lineup could reach. Exact once every slot is filled.
+    /// Open slots only count the players that could take them, and each of them once per team.
+    fn lower_bound(&self) -> f64 {
+        // The open places in a slot need that many different players, so its mismatch is at least the narrowest spread
+        // of the players already there and that many of the players who could join them
+        let mut mismatch = 0.0;
+        for slot_index in 0..self.positions.len() {
+            let position = self.positions[slot_index];
+            let mut lowest = f64::MAX;
+            let mut highest = f64::MIN;
+            let mut open = 0;
+            for team_index in 0..self.team_count {
+                match self.assignment[team_index][slot_index] {
+                    Some(p) => {
+                        lowest = lowest.min(self.smvp[p][position as usize]);
+                        highest = highest.max(self.smvp[p][position as usize]);
                     }
-                    panic!("
-                        No player was found in calculate_advanced during the selection process.\n
-                        Contact Metalface if you're seeing this.\n
-                        Chosen Player Name: {}\n,
-                        Matching Player ID: {}\n,
-                        Matching Player Name: {}\n",
-                        other_name_chosen,
-                        game[0][slot_index].player_id,
-                        game[0][slot_index].player_name)
+                    None => open += 1
                 }
             }
-            players.retain(|p| p.player_id != matched_player.player_id);
+            if open == 0 {
+                mismatch += highest - lowest;
+                continue;
+            }
+
+            let joining = self.by_smvp[position as usize].iter()
+                .filter(|p| (0..self.team_count).any(|t| self.assignment[t][slot_index].is_none() && self.can_take(**p, t, position)))
+                .map(|p| self.smvp[*p][position as usize]);
+            let narrowest = joining.clone().zip(joining.skip(open - 1))
+                .map(|(first, last)| highest.max(last) - lowest.min(first))
+                .fold(f64::MAX, f64::min);
+            if narrowest == f64::MAX {
+                return f64::MAX;
+            }
+            mismatch += narrowest;
+        }
 
-            game[team_match][slot_index].player_id = matched_player.player_id as i64;
-            game[team_match][slot_index].smvp = other_smvp_chosen;
-            game[team_match][slot_index].player_name = other_name_chosen;
+        // Each team's final total lies between its weakest and strongest possible fill of its open slots
+        let mut strongest_floor = f64::MIN;
+        let mut weakest_ceiling = f64::MAX;
+        for team_index in 0..self.team_count {
+            let mut floor = self.team_totals[team_index];
+            let mut ceiling = self.team_totals[team_index];
+            for position in 0..5 {
+                let open = (0..self.positions.len())
+                    .filter(|s| self.positions[*s] == position && self.assignment[team_index][*s].is_none())
+                    .count();
+                if open == 0 {
+                    continue;
+                }
+                let fitting = self.by_smvp[position as usize].iter()
+                    .filter(|p| self.can_take(**p, team_index, position))
+                    .map(|p| self.smvp[*p][position as usize]);
+                if fitting.clone().take(open).count() < open {
+                    return f64::MAX;
+                }
+                floor += fitting.clone().take(open).sum::<f64>();
+                ceiling += fitting.rev().take(open).sum::<f64>();
+            }
+            strongest_floor = strongest_floor.max(floor);
+            weakest_ceiling = weakest_ceiling.min(ceiling);
         }
 
-        // Switch the slot for team 0 and 1 if the modifer was used
-        if modifier_used { 
-            let switch = random_bool(0.5);
-            if switch {
-                let temp_player_slot_0 = game[0][slot_index].clone();
-                let temp_player_slot_1 = game[1][slot_index].clone();
+        mismatch + (strongest_floor - weakest_ceiling).max(0.0)
+    }
+}
+
+/// Local search run after any algorithm. Tries swapping every pair of players, between teams and between positions,
+/// and keeps the swap that lowers the team imbalance the most, until no swap helps or `max_iterations` swaps were made.
+/// Returns the refined game with the team imbalance before and after.
+pub fn refine_swaps(mut game : Vec<Vec<PlayerSlot>>, players : &[PlayerStats], modifiers_position : &Option<Vec<u64>>, modifiers_team : &Option<Vec<u64>>, model : &PredictionModel, max_iterations : u64) -> Result<(Vec<Vec<PlayerSlot>>, f64, f64), SelectorError> {
+
+    let position_locks = pair_modifiers(modifiers_position);
+    let team_locks = pair_modifiers(modifiers_team);
+
+    // Can the player in this slot move to the given team and position without breaking a modifier
+    let can_move = |slot : &PlayerSlot, team_index : usize, position : u64| {
+        let player_id = slot.player_id as u64;
+        position_locks.iter().all(|(id, locked)| *id != player_id || *locked == position)
+            && team_locks.iter().all(|(id, locked)| *id != player_id || *locked as usize == team_index)
+    };
+
+    let smvp_at = |slot : &PlayerSlot, position : u64| {
+        let player = players.iter().find(|player| player.player_id as i64 == slot.player_id).ok_or_else(|| not_in_pool(slot))?;
+        Ok::<f64, SelectorError>(calculate_smvp(retrieve_stat_block_from_position(position, player.clone()), position, model))
+    };
+
+    let before = team_imbalance(&game);
+    let mut current = before;
+
+    let slots: Vec<(usize, usize)> = game.iter().enumerate()
+        .flat_map(|(team_index, team)| (0..team.len()).map(move |slot_index| (team_index, slot_index)))
+        .filter(|(team_index, slot_index)| game[*team_index][*slot_index].player_id != -1)
+        .collect();
+
+    for _iteration in 0..max_iterations {
+        let mut best_swap = None;
+        let mut best_imbalance = current;
+
+        for (a_index, (a_team, a_slot)) in slots.iter().enumerate() {
+            for (b_team, b_slot) in slots.iter().skip(a_index + 1) {
+                let a = &game[*a_team][*a_slot];
+                let b = &game[*b_team][*b_slot];
+
+                // Same team and position would change nothing
+                if a_team == b_team && a.position == b.position {
+                    continue;
+                }
+                if !can_move(a, *b_team, b.position) || !can_move(b, *a_team, a.position) {
+                    continue;
+                }
+
+                let a_new_smvp = smvp_at(a, b.position)?;
+                let b_new_smvp = smvp_at(b, a.position)?;
+
+                let mut trial = game.clone();
+                trial[*a_team][*a_slot].smvp = b_new_smvp;
+                trial[*b_team][*b_slot].smvp = a_new_smvp;
+                let imbalance = team_imbalance(&trial);
 
-                game[0][slot_index] = temp_player_slot_1;
-                game[1][slot_index] = temp_player_slot_0;
+                if imbalance < best_imbalance - 1e-9 {
+                    best_imbalance = imbalance;
+                    best_swap = Some(((*a_team, *a_slot), (*b_team, *b_slot), a_new_smvp, b_new_smvp));
+                }
             }
         }
-    
+
+        let Some(((a_team, a_slot), (b_team, b_slot), a_new_smvp, b_new_smvp)) = best_swap else {
+            break;
+        };
+
+        // Swap who is in the slots, the slots themselves keep their positions
+        let a = game[a_team][a_slot].clone();
+        let b = game[b_team][b_slot].clone();
+        game[a_team][a_slot] = PlayerSlot { position: a.position, smvp: b_new_smvp, ..b };
+        game[b_team][b_slot] = PlayerSlot { position: b.position, smvp: a_new_smvp, ..a };
+        current = best_imbalance;
+    }
+
+    Ok((game, before, current))
+}
+
+/// The pool ran out before every slot was filled. select_teams checks the player count first, so this only happens to direct callers
+fn pool_ran_out(game : &[Vec<PlayerSlot>], given : usize) -> SelectorError {
+    return SelectorError::NotEnoughPlayers { needed: game.iter().map(Vec::len).sum(), given };
+}
+
+/// Advanced selection found nobody to match against. Either the pool ran out, or the SMVPs can't be compared
+fn no_match(game : &[Vec<PlayerSlot>], players : &[PlayerStats], position : u64, smvp : f64, model : &PredictionModel, given : usize) -> SelectorError {
+    if players.is_empty() {
+        return pool_ran_out(game, given);
     }
+    let position_name = set_position_from_index(position);
+    for player in players {
+        let player_smvp = calculate_smvp(retrieve_stat_block_from_position(position, player.clone()), position, model);
+        if !player_smvp.is_finite() {
+            return SelectorError::InvalidStats(format!("Player {} ({}) has an SMVP of {} as {}, check their stats.", player.player_name, player.player_id, player_smvp, position_name));
+        }
+    }
+    return SelectorError::InvalidStats(format!("A player placed as {} has an SMVP of {}, check their stats.", position_name, smvp));
+}
 
-    return game;
+/// A lineup holds a player who isn't in the pool it was made from
+fn not_in_pool(slot : &PlayerSlot) -> SelectorError {
+    return SelectorError::InvalidRequest(format!("Player {} ({}) is in the lineup but was not given as a player.", slot.player_name, slot.player_id));
+}
 
+/// Put a player in a slot with their smvp at the slot's position
+pub fn fill_slot(player : PlayerStats, slot : &mut PlayerSlot, model : &PredictionModel) {
+    let player_position_stats = retrieve_stat_block_from_position(slot.position, player.clone());
+    map_player_to_slot(player, player_position_stats, slot, model);
 }
 
-fn map_player_to_slot(player : PlayerStats, pos_stats: PositionStats, slot : &mut PlayerSlot) {
+fn map_player_to_slot(player : PlayerStats, pos_stats: PositionStats, slot : &mut PlayerSlot, model : &PredictionModel) {
     slot.player_id = player.player_id as i64;
     slot.player_name = player.player_name;
-    slot.smvp = calculate_smvp(pos_stats);
+    slot.smvp = calculate_smvp(pos_stats, slot.position, model);
+}
+
+/// Calculate the magic number that the selector will use to rank players. The way to calculate this is up for debate,
+/// so the weights come from the prediction model - the defaults give hit_diff * mvp + games / 100
+fn calculate_smvp(stats : PositionStats, position : u64, model : &PredictionModel) -> f64 {
+    return (model.position_weights[position as usize] * stats.hit_diff * stats.mvp) + (model.games_weight * stats.games_played as f64);
+}
+
+/// Drop repeated lineups and sort the rest fairest first, scored with `lineup_score`.
+/// Lineups that only differ by team order or by which of two same position slots a player is in count as repeats.
+pub fn rank_lineups(lineups : Vec<(Vec<Vec<PlayerSlot>>, Option<Refinement>)>) -> Vec<RankedLineup> {
+    let mut ranked: Vec<RankedLineup> = Vec::new();
+    let mut seen: Vec<Vec<Vec<(u64, i64)>>> = Vec::new();
+
+    for (lineup, refinement) in lineups {
+        let mut key: Vec<Vec<(u64, i64)>> = lineup.iter()
+            .map(|team| {
+                let mut team: Vec<(u64, i64)> = team.iter().map(|slot| (slot.position, slot.player_id)).collect();
+                team.sort();
+                team
+            })
+            .collect();
+        key.sort();
+
+        if seen.contains(&key) {
+            continue;
+        }
+        seen.push(key);
+        ranked.push(RankedLineup { score: lineup_score(&lineup), teams: lineup, refinement });
+    }
+
+    ranked.sort_by(|a, b| a.score.total_cmp(&b.score));
+    ranked
+}
+
+/// Difference in total smvp between the strongest and weakest team
+pub fn team_imbalance(game : &[Vec<PlayerSlot>]) -> f64 {
+    let totals: Vec<f64> = game.iter().map(|team| team.iter().map(|slot| slot.smvp).sum()).collect();
+    totals.iter().cloned().fold(f64::MIN, f64::max) - totals.iter().cloned().fold(f64::MAX, f64::min)
+}
+
+/// For every slot, the difference in smvp between the best and worst player filling it across the teams, summed up
+pub fn slot_mismatch(game : &[Vec<PlayerSlot>]) -> f64 {
+    let mut mismatch = 0.0;
+    for slot_index in 0..game[0].len() {
+        let values: Vec<f64> = game.iter().map(|team| team[slot_index].smvp).collect();
+        mismatch += values.iter().cloned().fold(f64::MIN, f64::max) - values.iter().cloned().fold(f64::MAX, f64::min);
+    }
+    mismatch
 }
 
-/// Calculate the magic number that the selector will use to rank players. The way to calculate this is up for debate
-fn calculate_smvp(stats : PositionStats) -> f64 {
-    return (stats.hit_diff * stats.mvp) + (stats.games_played as f64 / 100.0);
+/// How unfair a lineup is, lower is better. Used to compare lineups against each other
+pub fn lineup_score(game : &[Vec<PlayerSlot>]) -> f64 {
+    team_imbalance(game) + slot_mismatch(game)
 }
 
-fn retrieve_stat_block_from_position(postion : u64, player : PlayerStats) -> PositionStats {
+pub fn retrieve_stat_block_from_position(postion : u64, player : PlayerStats) -> PositionStats {
     match postion {
         0 => {return player.commander_stats}
         1 => {return player.heavy_stats}
         2 => {return player.scout_stats}
         3 => {return player.ammo_stats}
         4 => {return player.medic_stats}
-        _ => {panic!("This is wrong.")}
+        _ => {unreachable!("Position index {} does not exist, positions are 0 to 4.", postion)}
+    }
+}
+
+/// Turn a flat modifier argument (player id followed by a value, repeated) into pairs
+fn pair_modifiers(modifiers : &Option<Vec<u64>>) -> Vec<(u64, u64)> {
+    match modifiers {
+        Some(modifiers) => {
+            let mut pairs: Vec<(u64, u64)> = Vec::new();
+            for pair in modifiers.chunks_exact(2) {
+                if !pairs.contains(&(pair[0], pair[1])) {
+                    pairs.push((pair[0], pair[1]));
+                }
+            }
+            pairs
+        }
+        None => Vec::new()
     }
 }
 
-fn assign_position_modifier_to_slot(mut game : Vec<Vec<PlayerSlot>>, mut players : Vec<PlayerStats>, modifiers_position : &Vec<u64>) -> (Vec<Vec<PlayerSlot>>, Vec<PlayerStats>) {
+/// Place every player with a position or team modifier into a slot before the algorithm runs.
+/// Position only locks are spread over the teams, `team_order` decides which team wins a tie.
+/// Returns the game and the remaining player pool.
+fn assign_modifiers(mut game : Vec<Vec<PlayerSlot>>, mut players : Vec<PlayerStats>, modifiers_position : &Option<Vec<u64>>, modifiers_team : &Option<Vec<u64>>, team_order : &[usize], model : &PredictionModel) -> Result<(Vec<Vec<PlayerSlot>>, Vec<PlayerStats>), SelectorError> {
+
+    let position_locks = pair_modifiers(modifiers_position);
+    let team_locks = pair_modifiers(modifiers_team);
+
+    for (player_id, team) in &team_locks {
+        if *team as usize >= game.len() {
+            return Err(SelectorError::InvalidModifier(format!("Player {} is locked to team {}, but there are only {} teams (team indexes start at 0).", player_id, team, game.len())));
+        }
+        if let Some((_, other_team)) = team_locks.iter().find(|(other_id, other_team)| other_id == player_id && other_team != team) {
+            return Err(SelectorError::InvalidModifier(format!("Player {} is locked to both team {} and team {}.", player_id, team, other_team)));
+        }
+    }
+
+    for (player_id, position) in &position_locks {
+        if !game.iter().flatten().any(|slot| slot.position == *position) {
+            return Err(SelectorError::InvalidModifier(format!("Player {} is locked to position {}, which this game type does not use.", player_id, position)));
+        }
+        if let Some((_, other_position)) = position_locks.iter().find(|(other_id, other_position)| other_id == player_id && other_position != position) {
+            return Err(SelectorError::InvalidModifier(format!("Player {} is locked to both position {} and position {}.", player_id, position, other_position)));
+        }
+    }
+
+    let team_lock_for = |player_id : u64| team_locks.iter().find(|(id, _)| *id == player_id).map(|(_, team)| *team as usize);
+
+    // Players locked to both a team and a position have exactly one choice, so place them first
+    for (player_id, position) in &position_locks {
+        let Some(team_index) = team_lock_for(*player_id) else { continue };
+        let player = take_player_from_pool(&mut players, *player_id)?;
+
+        match game[team_index].iter().position(|slot| slot.position == *position && slot.player_id == -1) {
+            Some(slot_index) => {
+                let player_position_stats = retrieve_stat_block_from_position(*position, player.clone());
+                map_player_to_slot(player, player_position_stats, &mut game[team_index][slot_index], model);
+            }
+            None => {
+                return Err(SelectorError::InvalidModifier(format!("Player {} is locked to team {} and position {}, but that team has no free {} slot left. Check the position and team modifiers for conflicts.",
+                    player_id, team_index, position, set_position_from_index(*position))));
+            }
+        }
+    }
+
+    // Team only locks are placed last, so each team keeps a free slot for every one of them
+    let mut team_only_locks = vec![0; game.len()];
+    for (player_id, team) in &team_locks {
+        if !position_locks.iter().any(|(id, _)| id == player_id) {
+            team_only_locks[*team as usize] += 1;
+        }
+    }
+
+    // Position only locks go on the team with the fewest modified players that still has a free slot for that position
+    for (player_id, position) in &position_locks {
+        if team_lock_for(*player_id).is_some() {
+            continue;
+        }
+        let player = take_player_from_pool(&mut players, *player_id)?;
+
+        let free_slot = team_order.iter()
+            .filter(|team_index| game[**team_index].iter().filter(|slot| slot.player_id == -1).count() > team_only_locks[**team_index])
+            .filter_map(|team_index| {
+                let team = &game[*team_index];
+                team.iter().position(|slot| slot.position == *position && slot.player_id == -1).map(|slot_index| (*team_index, slot_index))
+            })
+            .min_by_key(|(team_index, _)| game[*team_index].iter().filter(|slot| slot.player_id != -1).count());
+
+        match free_slot {
+            Some((team_index, slot_index)) => {
+                let player_position_stats = retrieve_stat_block_from_position(*position, player.clone());
+                map_player_to_slot(player, player_position_stats, &mut game[team_index][slot_index], model);
+            }
+            None => {
+                return Err(SelectorError::InvalidModifier(format!("Player {} is locked to position {}, but every {} slot is already taken by other modifiers.",
+                    player_id, position, set_position_from_index(*position))));
+            }
+        }
+    }
 
-    for modifiers_index in (0..modifiers_position.len()).step_by(2) {
-        let player_id = modifiers_position[modifiers_index];
-        let position = modifiers_position[modifiers_index+1];
+    // Team only locks take the free slot on their team where they play best
+    for (player_id, team) in &team_locks {
+        if position_locks.iter().any(|(id, _)| id == player_id) {
+            continue;
+        }
+        let team_index = *team as usize;
+        let player = take_player_from_pool(&mut players, *player_id)?;
 
-        let player = players.iter().find(|player| player.player_id == player_id).expect("selection: assign: Could not find passed player id in pool.").clone();
-        //TODO: There has to be a better way.
-        // Do I assign a player to a random team or always only the first team? 
-        // Random team causes problems as the advanced selector only matches with the first team.
-        let team_index = 0;
+        let best_slot = game[team_index].iter().enumerate()
+            .filter(|(_, slot)| slot.player_id == -1)
+            .map(|(slot_index, slot)| (slot_index, calculate_smvp(retrieve_stat_block_from_position(slot.position, player.clone()), slot.position, model)))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
 
-        for slot_index in 0..game[team_index].len() {
-            if game[team_index][slot_index].position == position && game[team_index][slot_index].player_id == -1 {
+        match best_slot {
+            Some((slot_index, _)) => {
                 let player_position_stats = retrieve_stat_block_from_position(game[team_index][slot_index].position, player.clone());
-                players.retain(|p| p.player_id != player.player_id);
-                map_player_to_slot(player.clone(), player_position_stats, &mut game[team_index][slot_index]);
-                break;
+                map_player_to_slot(player, player_position_stats, &mut game[team_index][slot_index], model);
+            }
+            None => {
+                return Err(SelectorError::InvalidModifier(format!("Player {} is locked to team {}, but every slot on that team is already taken by other modifiers.", player_id, team_index)));
             }
         }
+    }
+
+    Ok((game, players))
+}
+
+/// Remove a modified player from the pool, they must have been passed in with -p or -n
+fn take_player_from_pool(players : &mut Vec<PlayerStats>, player_id : u64) -> Result<PlayerStats, SelectorError> {
+    let player = players.iter().find(|player| player.player_id == player_id)
+        .ok_or_else(|| SelectorError::InvalidModifier(format!("Player {} has a modifier but was not given with -p or -n.", player_id)))?
+        .clone();
+    players.retain(|p| p.player_id != player_id);
+    Ok(player)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{create_game, test_support::{random_players, ranked_players}, GameType};
+
+    fn player_ids(game : &[Vec<PlayerSlot>]) -> Vec<Vec<i64>> {
+        return game.iter().map(|team| team.iter().map(|slot| slot.player_id).collect()).collect();
+    }
+
+    /// The best lineup_score of every way to put the players in the slots that keeps the locks, by trying them all
+    fn brute_force_best(game : &[Vec<PlayerSlot>], players : &[PlayerStats], position_locks : &[(u64, u64)], team_locks : &[(u64, u64)]) -> f64 {
+        let slots: Vec<(usize, usize)> = (0..game.len()).flat_map(|team| (0..game[team].len()).map(move |slot| (team, slot))).collect();
+        let mut order: Vec<usize> = (0..players.len()).collect();
+        let mut best = f64::MAX;
+        permute(&mut order, 0, &mut |order| {
+            let mut lineup = game.to_vec();
+            for (&(team, slot), &p) in slots.iter().zip(order.iter()) {
+                let player = &players[p];
+                let position = lineup[team][slot].position;
+                if position_locks.iter().any(|(id, locked)| *id == player.player_id && *locked != position)
+                    || team_locks.iter().any(|(id, locked)| *id == player.player_id && *locked != team as u64) {
+                    return;
+                }
+                let stats = retrieve_stat_block_from_position(position, player.clone());
+                map_player_to_slot(player.clone(), stats, &mut lineup[team][slot], &PredictionModel::default());
+            }
+            best = best.min(lineup_score(&lineup));
+        });
+        return best;
+    }
+
+    fn permute(order : &mut Vec<usize>, from : usize, visit : &mut dyn FnMut(&[usize])) {
+        if from == order.len() {
+            visit(order);
+            return;
+        }
+        for i in from..order.len() {
+            order.swap(from, i);
+            permute(order, from + 1, visit);
+            order.swap(from, i);
+        }
+    }
+
+    fn optimal_score(game : &[Vec<PlayerSlot>], players : &[PlayerStats], modifiers_position : &Option<Vec<u64>>, modifiers_team : &Option<Vec<u64>>) -> f64 {
+        let (lineups, timed_out) = optimal_selection(game.to_vec(), players.to_vec(), modifiers_position, modifiers_team, &PredictionModel::default(), Duration::from_secs(60), 1).unwrap();
+        assert!(!timed_out);
+        return lineup_score(&lineups[0]);
+    }
+
+    #[test]
+    fn optimal_selection_finds_the_best_lineup() {
+        let game = create_game(GameType::Sm5_QueenBee, 2);
+        for seed in 0..5 {
+            let players = random_players(8, seed);
+            let expected = brute_force_best(&game, &players, &[], &[]);
+            assert!((optimal_score(&game, &players, &None, &None) - expected).abs() < 1e-9, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn optimal_selection_finds_the_best_lineup_with_locks() {
+        let game = create_game(GameType::Sm5_QueenBee, 2);
+        for seed in 0..5 {
+            let players = random_players(8, seed);
+            let expected = brute_force_best(&game, &players, &[(1, 4), (2, 3)], &[(1, 0), (3, 1), (4, 1)]);
+            let score = optimal_score(&game, &players, &Some(vec![1, 4, 2, 3]), &Some(vec![1, 0, 3, 1, 4, 1]));
+            assert!((score - expected).abs() < 1e-9, "seed {}", seed);
+        }
+    }
 
+    #[test]
+    fn simple_selection_drafts_in_snake_order() {
+        let game = create_game(GameType::Sm5_10_Player, 2);
+        let lineup = simple_selection(game, ranked_players(10), &None, &None, &PredictionModel::default()).unwrap();
+        // Team 0 picks first in even slots and team 1 in odd ones
+        assert_eq!(player_ids(&lineup), vec![vec![10, 7, 6, 3, 2], vec![9, 8, 5, 4, 1]]);
     }
 
-    (game, players)
-}
\ No newline at end of file
+    #[test]
+    fn simple_selection_drafts_in_snake_order_with_three_teams() {
+        let game = create_game(GameType::Sm5_10_Player, 3);
+        let lineup = simple_selection(game, ranked_players(15), &None, &None, &PredictionModel::default()).unwrap();
+        assert_eq!(player_ids(&lineup), vec![vec![15, 10, 9, 4, 3], vec![14, 11, 8, 5, 2], vec![13, 12, 7, 6, 1]]);
+    }
+
+    fn team_of(game : &[Vec<PlayerSlot>], player_id : i64) -> usize {
+        return game.iter().position(|team| team.iter().any(|slot| slot.player_id == player_id)).unwrap();
+    }
+
+    #[test]
+    fn team_locks_are_kept() {
+        let game = create_game(GameType::Sm5_12_Player, 2);
+        let modifiers_team = Some(vec![12, 0, 11, 0, 10, 0, 1, 1]);
+        for seed in 0..5 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let advanced = calculate_advanced(game.clone(), ranked_players(12), &None, &modifiers_team, &PredictionModel::default(), &mut rng).unwrap();
+            let random = random_random(game.clone(), ranked_players(12), &None, &modifiers_team, &PredictionModel::default(), &mut rng).unwrap();
+            for lineup in [advanced, random] {
+                assert_eq!([12, 11, 10, 1].map(|player_id| team_of(&lineup, player_id)), [0, 0, 0, 1], "seed {}", seed);
+            }
+        }
+
+        // Team 0 is filled by team locks, so the position lock has to go on team 1
+        let modifiers_position = Some(vec![12, 0]);
+        let modifiers_team = Some((1..=6).flat_map(|player_id| [player_id, 0]).collect());
+        let model = PredictionModel::default();
+        for seed in 0..5 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut lineups = vec![
+                calculate_advanced(game.clone(), ranked_players(12), &modifiers_position, &modifiers_team, &model, &mut rng).unwrap(),
+                random_random(game.clone(), ranked_players(12), &modifiers_position, &modifiers_team, &model, &mut rng).unwrap()
+            ];
+            // Simple and optimal selection don't use the seed, and a lineup that keeps the locks is all that's checked here
+            if seed == 0 {
+                lineups.push(simple_selection(game.clone(), ranked_players(12), &modifiers_position, &modifiers_team, &model).unwrap());
+                lineups.push(optimal_selection(game.clone(), ranked_players(12), &modifiers_position, &modifiers_team, &model, Duration::from_millis(500), 1).unwrap().0.remove(0));
+            }
+            for lineup in lineups {
+                assert!((1..=6).all(|player_id| team_of(&lineup, player_id) == 0), "seed {}", seed);
+                assert!(lineup[1].iter().any(|slot| slot.player_id == 12 && slot.position == 0), "seed {}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn conflicting_locks_are_invalid_modifiers() {
+        let game = create_game(GameType::Sm5_10_Player, 2);
+        let conflicts = [
+            // A team that doesn't exist
+            (None, Some(vec![1, 2])),
+            // Two teams for one player
+            (None, Some(vec![1, 0, 1, 1])),
+            // Two positions for one player
+            (Some(vec![1, 0, 1, 4]), None),
+            // Two commanders on the same team
+            (Some(vec![1, 0, 2, 0]), Some(vec![1, 0, 2, 0])),
+            // Three commanders for two commander slots
+            (Some(vec![1, 0, 2, 0, 3, 0]), None)
+        ];
+        for (modifiers_position, modifiers_team) in conflicts {
+            let mut rng = StdRng::seed_from_u64(0);
+            let result = calculate_advanced(game.clone(), ranked_players(10), &modifiers_position, &modifiers_team, &PredictionModel::default(), &mut rng);
+            assert!(matches!(result, Err(SelectorError::InvalidModifier(_))), "{:?} {:?}", modifiers_position, modifiers_team);
+        }
+    }
+
+    #[test]
+    fn unusable_stats_are_not_a_player_count_error() {
+        let game = create_game(GameType::Sm5_12_Player, 2);
+        let mut players = ranked_players(12);
+        let broken = &mut players[3];
+        for stats in [&mut broken.commander_stats, &mut broken.heavy_stats, &mut broken.scout_stats, &mut broken.ammo_stats, &mut broken.medic_stats] {
+            stats.mvp = f64::NAN;
+        }
+        for seed in 0..5 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let result = calculate_advanced(game.clone(), players.clone(), &None, &None, &PredictionModel::default(), &mut rng);
+            assert!(matches!(result, Err(SelectorError::InvalidStats(_))), "seed {}", seed);
+        }
+    }
+
+    /// Fill the slots with the players in order, so with ranked players the last team is the strongest
+    fn in_order(mut game : Vec<Vec<PlayerSlot>>, players : &[PlayerStats], model : &PredictionModel) -> Vec<Vec<PlayerSlot>> {
+        for (slot, player) in game.iter_mut().flatten().zip(players) {
+            fill_slot(player.clone(), slot, model);
+        }
+        return game;
+    }
+
+    #[test]
+    fn refine_swaps_keeps_locks_and_never_raises_the_imbalance() {
+        let game = create_game(GameType::Sm5_12_Player, 2);
+        let model = PredictionModel::default();
+        let modifiers_position = Some(vec![12, 0, 3, 4]);
+        let modifiers_team = Some(vec![12, 1, 1, 0, 2, 0]);
+        for seed in 0..5 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let players = random_players(12, seed);
+            let lineup = random_random(game.clone(), players.clone(), &modifiers_position, &modifiers_team, &model, &mut rng).unwrap();
+
+            let (refined, before, after) = refine_swaps(lineup.clone(), &players, &modifiers_position, &modifiers_team, &model, 100).unwrap();
+            assert_eq!(before, team_imbalance(&lineup), "seed {}", seed);
+            assert!((after - team_imbalance(&refined)).abs() < 1e-9, "seed {}", seed);
+            assert!(after <= before, "seed {}", seed);
+
+            assert!(refined[1].iter().any(|slot| slot.player_id == 12 && slot.position == 0), "seed {}", seed);
+            assert!(refined.iter().flatten().any(|slot| slot.player_id == 3 && slot.position == 4), "seed {}", seed);
+            assert_eq!([1, 2].map(|player_id| team_of(&refined, player_id)), [0, 0], "seed {}", seed);
+
+            let mut refined_ids: Vec<i64> = refined.iter().flatten().map(|slot| slot.player_id).collect();
+            refined_ids.sort();
+            assert_eq!(refined_ids, (1..=12).collect::<Vec<i64>>(), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn refine_swaps_stops_at_max_iterations() {
+        let model = PredictionModel::default();
+        let players = ranked_players(12);
+        let lineup = in_order(create_game(GameType::Sm5_12_Player, 2), &players, &model);
+
+        let (unchanged, before, after) = refine_swaps(lineup.clone(), &players, &None, &None, &model, 0).unwrap();
+        assert_eq!(player_ids(&unchanged), player_ids(&lineup));
+        assert_eq!(before, after);
+
+        // One swap moves exactly two players
+        let (one_swap, _, one_swap_after) = refine_swaps(lineup.clone(), &players, &None, &None, &model, 1).unwrap();
+        let moved = lineup.iter().flatten().zip(one_swap.iter().flatten()).filter(|(old, new)| old.player_id != new.player_id).count();
+        assert_eq!(moved, 2);
+        assert!(one_swap_after < before);
+
+        let (_, _, settled_after) = refine_swaps(lineup, &players, &None, &None, &model, 100).unwrap();
+        assert!(settled_after < one_swap_after);
+    }
+}
@@ -0,0 +1,199 @@
+use std::{io::Read, panic::{self, AssertUnwindSafe}, sync::{Arc, Mutex}, thread, time::Duration};
+
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{error::SelectorError, history::retrieve_player_stats, output::error_as_json, request::{parse_request, SelectionInput}, source::{open_data_source, DataSource, MemoryCache, MemorySource}, DatabaseOptions, MVPCalculationMode, NGamesScope, NamedOption, SourceOptions, StatsParameters};
+
+/// Request bodies bigger than this are refused, a selection request is a few hundred bytes
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+/// Turns a selection request into the same JSON the command line prints
+pub type SelectHandler<'a> = dyn Fn(SelectionInput, &mut dyn DataSource) -> Result<String, SelectorError> + Sync + 'a;
+
+pub struct ServerOptions {
+    /// Address and port to listen on
+    pub bind: String,
+    /// How many requests are handled at once. Each worker keeps its own database connection
+    pub workers: usize,
+    /// How long players' scorecards are kept before reading them again
+    pub stats_max_age: Duration,
+    /// Stats settings for GET /players/{id}/stats when the query string doesn't give them
+    pub stats: StatsParameters,
+    /// The most a request may ask for
+    pub limits: RequestLimits
+}
+
+/// Caps on the options a selection request can set, so one client can't tie up a worker for as long as they like
+pub struct RequestLimits {
+    pub max_candidates: u64,
+    pub max_time_budget_ms: u64,
+    pub max_refine_iterations: u64
+}
+
+/// Serve selections over HTTP until the process is stopped. Every worker thread owns a data source, so Postgres connections
+/// are made once per worker and reused, and all of them share one in memory cache of what has been read
+pub fn serve(options : &ServerOptions, source_options : &SourceOptions, database : &DatabaseOptions, select : &SelectHandler<'_>) -> Result<(), SelectorError> {
+    // Sources are opened before listening so bad settings fail straight away
+    let mut sources = Vec::new();
+    for _ in 0..options.workers {
+        sources.push(open_data_source(source_options, database)?);
+    }
+
+    let server = Server::http(&options.bind).map_err(|error| SelectorError::Config(format!("Could not listen on {}: {}", options.bind, error)))?;
+    let cache = Arc::new(Mutex::new(MemoryCache::default()));
+    eprintln!("Listening on http://{} with {} workers", options.bind, options.workers);
+
+    thread::scope(|scope| {
+        for source in sources {
+            let server = &server;
+            let mut source = MemorySource::shared(source, cache.clone(), options.stats_max_age);
+            scope.spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(request, &mut source, options, select);
+                }
+            });
+        }
+    });
+
+    return Ok(());
+}
+
+fn handle(mut request : Request, source : &mut dyn DataSource, options : &ServerOptions, select : &SelectHandler<'_>) {
+    // A bug in selection must not take the worker down with it, the client gets a 500 and the worker carries on
+    let (status, body) = panic::catch_unwind(AssertUnwindSafe(|| route(&mut request, source, options, select)))
+        .unwrap_or_else(|_| (500, not_routed("internal", "The server hit an internal error handling this request.".to_string())));
+
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    let response = Response::from_string(body).with_status_code(status).with_header(content_type);
+    if let Err(error) = request.respond(response) {
+        eprintln!("Could not send a response: {}", error);
+    }
+}
+
+/// The status code and JSON body for a request
+fn route(request : &mut Request, source : &mut dyn DataSource, options : &ServerOptions, select : &SelectHandler<'_>) -> (u16, String) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let result = match (request.method(), segments.as_slice()) {
+        (Method::Get, ["health"]) => {
+            return health(source);
+        }
+        (Method::Post, ["select"]) => {
+            read_body(request)
+                .and_then(|body| parse_request(&body))
+                .and_then(|input| check_limits(&input, &options.limits).map(|_| input))
+                .and_then(|input| check_stats(&input, &options.stats).map(|_| input))
+                .and_then(|input| select(input, source))
+        }
+        (Method::Get, ["players", player_id, "stats"]) => {
+            player_stats(source, player_id, query, &options.stats)
+        }
+        (_, ["health"]) | (_, ["select"]) | (_, ["players", _, "stats"]) => {
+            return (405, not_routed("method_not_allowed", format!("{} is not allowed on {}", request.method(), path)));
+        }
+        _ => {
+            return (404, not_routed("not_found", format!("Nothing is at {}", path)));
+        }
+    };
+
+    match result {
+        Ok(body) => (200, body),
+        Err(error) => (status_code(&error), error_as_json(&error))
+    }
+}
+
+/// Whether the data source can be reached, so a load balancer or the website can check on the server
+fn health(source : &mut dyn DataSource) -> (u16, String) {
+    match source.is_available() {
+        Ok(true) => (200, json!({ "status": "ok" }).to_string()),
+        Ok(false) => (503, json!({ "status": "unavailable" }).to_string()),
+        Err(error) => (status_code(&error), error_as_json(&error))
+    }
+}
+
+/// One player's stats at every position. The query string takes mvp_calculation_mode and the stats options, named as in a request
+fn player_stats(source : &mut dyn DataSource, player_id : &str, query : &str, default_stats : &StatsParameters) -> Result<String, SelectorError> {
+    let player_id: u64 = player_id.parse().map_err(|_| SelectorError::InvalidRequest(format!("Player id must be a number, got {}", player_id)))?;
+
+    let mut stats = default_stats.clone();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let invalid = || SelectorError::InvalidRequest(format!("Invalid value for {}: {}", name, value));
+        match name {
+            "mvp_calculation_mode" => stats.mvp_calc_mode = MVPCalculationMode::from_name(value).ok_or_else(invalid)?,
+            "n_games" => stats.n_games = value.parse().map_err(|_| invalid())?,
+            "n_games_scope" => stats.n_games_scope = NGamesScope::from_name(value).ok_or_else(invalid)?,
+            "shrinkage_strength" => stats.shrinkage_strength = value.parse().map_err(|_| invalid())?,
+            "half_life_days" => stats.half_life_days = value.parse().map_err(|_| invalid())?,
+            _ => return Err(SelectorError::InvalidRequest(format!("Unknown query parameter {}", name)))
+        }
+    }
+    // Bad settings from a client are the client's mistake, not the server's
+    let stats = StatsParameters::new(stats.mvp_calc_mode, stats.n_games, stats.n_games_scope, stats.shrinkage_strength, stats.half_life_days)
+        .map_err(|error| SelectorError::InvalidRequest(error.to_string()))?;
+
+    let player_stats = retrieve_player_stats(source, &[player_id], &stats)?;
+    return Ok(serde_json::to_string_pretty(&player_stats[0]).unwrap());
+}
+
+fn check_limits(input : &SelectionInput, limits : &RequestLimits) -> Result<(), SelectorError> {
+    let options = &input.options;
+    if options.model.is_some() {
+        return Err(SelectorError::InvalidRequest("The server doesn't load models named in requests, start it with --model instead.".to_string()));
+    }
+
+    let over_limit = |name : &str, value : Option<u64>, limit : u64| -> Result<(), SelectorError> {
+        match value {
+            Some(value) if value > limit => Err(SelectorError::InvalidRequest(format!("{} can be at most {} on this server, got {}.", name, limit, value))),
+            _ => Ok(())
+        }
+    };
+    over_limit("candidates", options.candidates, limits.max_candidates)?;
+    over_limit("time_budget_ms", options.time_budget_ms, limits.max_time_budget_ms)?;
+    over_limit("refine_iterations", options.refine_iterations, limits.max_refine_iterations)?;
+    return Ok(());
+}
+
+/// Checks the request's stats options the same way the command line's are checked, so bad ones are the client's mistake
+fn check_stats(input : &SelectionInput, default_stats : &StatsParameters) -> Result<(), SelectorError> {
+    let options = &input.options;
+    StatsParameters::new(
+        default_stats.mvp_calc_mode.clone(),
+        options.n_games.unwrap_or(default_stats.n_games),
+        options.n_games_scope.clone().unwrap_or(default_stats.n_games_scope.clone()),
+        options.shrinkage_strength.unwrap_or(default_stats.shrinkage_strength),
+        options.half_life_days.unwrap_or(default_stats.half_life_days)
+    ).map_err(|error| SelectorError::InvalidRequest(error.to_string()))?;
+    return Ok(());
+}
+
+fn read_body(request : &mut Request) -> Result<String, SelectorError> {
+    let mut body = String::new();
+    request.as_reader().take(MAX_BODY_BYTES + 1).read_to_string(&mut body)
+        .map_err(|error| SelectorError::InvalidRequest(format!("Could not read the request body: {}", error)))?;
+    if body.len() as u64 > MAX_BODY_BYTES {
+        return Err(SelectorError::InvalidRequest(format!("The request body is over {} bytes.", MAX_BODY_BYTES)));
+    }
+    return Ok(body);
+}
+
+/// Requests that were wrong go back as 400, a data source that can't be reached as 503 and anything else as 500
+fn status_code(error : &SelectorError) -> u16 {
+    match error {
+        SelectorError::InvalidRequest(_)
+        | SelectorError::NotEnoughPlayers { .. }
+        | SelectorError::UnknownPlayer(_)
+        | SelectorError::InvalidModifier(_)
+        | SelectorError::UnsupportedAlgorithm(_) => 400,
+        SelectorError::Connection(_) => 503,
+        SelectorError::Config(_) | SelectorError::Query(_) | SelectorError::Training(_) | SelectorError::InvalidStats(_) => 500
+    }
+}
+
+/// Error body for failures that aren't a SelectorError, like URLs with no endpoint, shaped like the other errors
+fn not_routed(kind : &str, message : String) -> String {
+    return json!({ "error": { "kind": kind, "message": message } }).to_string();
+}
@@ -0,0 +1,330 @@
+use std::{collections::HashMap, fs, path::PathBuf, sync::{Arc, Mutex, MutexGuard, PoisonError}, time::{Duration, Instant}};
+
+use postgres::Client;
+
+use crate::{cache::CachedSource, db::{create_db_client, query_history, query_player_ids_by_name, query_player_scorecards, query_player_scorecards_since, query_population_stats, set_database_params}, error::SelectorError, stats::PopulationTotals, DataSourceKind, DatabaseOptions, PopulationStats, Scorecard, SourceOptions};
+
+/// Somewhere scorecards can be read from. Sources are Send so the server can hand one to each worker thread
+pub trait DataSource: Send {
+    /// Every scorecard for the given players, newest first
+    fn player_scorecards(&mut self, player_ids : &[u64]) -> Result<Vec<Scorecard>, SelectorError>;
+
+    /// Every scorecard, oldest game first with each game's scorecards together
+    fn history(&mut self) -> Result<Vec<Scorecard>, SelectorError>;
+
+    /// Average MVP and hit diff at each position across every scorecard. Positions nobody has played get 7.0 and 1.0
+    fn population_stats(&mut self) -> Result<PopulationStats, SelectorError>;
+
+    /// The ids of every player who has played under this name, ignoring case
+    fn player_ids_by_name(&mut self, player_name : &str) -> Result<Vec<u64>, SelectorError>;
+
+    /// Scorecards for the given players from games played at or after since (seconds since the Unix epoch), newest first
+    fn player_scorecards_since(&mut self, player_ids : &[u64], since : i64) -> Result<Vec<Scorecard>, SelectorError> {
+        return Ok(self.player_scorecards(player_ids)?.into_iter().filter(|card| card.played_at >= since).collect());
+    }
+
+    /// Whether the source can be reached right now. Settings that are wrong are still an error
+    fn is_available(&mut self) -> Result<bool, SelectorError> {
+        return Ok(true);
+    }
+}
+
+pub fn open_data_source(source : &SourceOptions, database : &DatabaseOptions) -> Result<Box<dyn DataSource>, SelectorError> {
+    let inner: Box<dyn DataSource> = match &source.data_source {
+        DataSourceKind::Postgres => Box::new(PostgresSource { database: database.clone(), client: None }),
+        DataSourceKind::File(path) => Box::new(FileSource::load(path)?)
+    };
+
+    return match &source.cache {
+        Some(path) => Ok(Box::new(CachedSource::open(inner, path, source.cache_max_age)?)),
+        None => Ok(inner)
+    };
+}
+
+/// The lfstats database. Nothing connects until the first query, so runs that only use new players work offline
+pub struct PostgresSource {
+    database: DatabaseOptions,
+    client: Option<Client>
+}
+
+impl PostgresSource {
+    fn client(&mut self) -> Result<&mut Client, SelectorError> {
+        // A long running server outlives connections, so a dropped one is made again
+        if self.client.as_ref().is_some_and(|client| client.is_closed()) {
+            self.client = None;
+        }
+        if self.client.is_none() {
+            self.client = Some(create_db_client(set_database_params(&self.database)?)?);
+        }
+        return Ok(self.client.as_mut().unwrap());
+    }
+}
+
+impl DataSource for PostgresSource {
+    fn player_scorecards(&mut self, player_ids : &[u64]) -> Result<Vec<Scorecard>, SelectorError> {
+        return query_player_scorecards(self.client()?, player_ids);
+    }
+
+    fn history(&mut self) -> Result<Vec<Scorecard>, SelectorError> {
+        return query_history(self.client()?);
+    }
+
+    fn population_stats(&mut self) -> Result<PopulationStats, SelectorError> {
+        return query_population_stats(self.client()?);
+    }
+
+    fn player_ids_by_name(&mut self, player_name : &str) -> Result<Vec<u64>, SelectorError> {
+        return query_player_ids_by_name(self.client()?, player_name);
+    }
+
+    fn player_scorecards_since(&mut self, player_ids : &[u64], since : i64) -> Result<Vec<Scorecard>, SelectorError> {
+        return query_player_scorecards_since(self.client()?, player_ids, since);
+    }
+
+    fn is_available(&mut self) -> Result<bool, SelectorError> {
+        match self.client() {
+            Ok(_) => Ok(true),
+            Err(SelectorError::Connection(message)) => {
+                eprintln!("{}", message);
+                Ok(false)
+            }
+            Err(error) => Err(error)
+        }
+    }
+}
+
+/// Scorecards exported to a file, in any order. Files ending in .csv are read as CSV with a header row,
+/// anything else as a JSON array
+pub struct FileSource {
+    scorecards: Vec<Scorecard>
+}
+
+impl FileSource {
+    pub fn load(path : &PathBuf) -> Result<FileSource, SelectorError> {
+        let is_csv = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+
+        let scorecards = if is_csv {
+            let mut reader = csv::Reader::from_path(path).map_err(|error| SelectorError::Config(format!("Could not read scorecards file {}: {}", path.display(), error)))?;
+            reader.deserialize().collect::<Result<Vec<Scorecard>, _>>()
+                .map_err(|error| SelectorError::Config(format!("Scorecards file {} is not valid: {}", path.display(), error)))?
+        } else {
+            let contents = fs::read_to_string(path).map_err(|error| SelectorError::Config(format!("Could not read scorecards file {}: {}", path.display(), error)))?;
+            serde_json::from_str(&contents).map_err(|error| SelectorError::Config(format!("Scorecards file {} is not valid: {}", path.display(), error)))?
+        };
+
+        return Ok(FileSource { scorecards });
+    }
+}
+
+impl DataSource for FileSource {
+    fn player_scorecards(&mut self, player_ids : &[u64]) -> Result<Vec<Scorecard>, SelectorError> {
+        let mut scorecards: Vec<Scorecard> = self.scorecards.iter().filter(|card| player_ids.contains(&card.player_id)).cloned().collect();
+        scorecards.sort_by(|a, b| b.played_at.cmp(&a.played_at).then(b.game_id.cmp(&a.game_id)));
+        return Ok(scorecards);
+    }
+
+    fn history(&mut self) -> Result<Vec<Scorecard>, SelectorError> {
+        // Oldest game first with each game's scorecards together, the same order as query_history
+        let mut scorecards = self.scorecards.clone();
+        scorecards.sort_by(|a, b| a.played_at.cmp(&b.played_at).then(a.game_id.cmp(&b.game_id)));
+        return Ok(scorecards);
+    }
+
+    fn population_stats(&mut self) -> Result<PopulationStats, SelectorError> {
+        let mut totals = PopulationTotals::default();
+        for card in &self.scorecards {
+            totals.add(card);
+        }
+        return Ok(totals.averages());
+    }
+
+    fn player_ids_by_name(&mut self, player_name : &str) -> Result<Vec<u64>, SelectorError> {
+        let player_name = player_name.to_lowercase();
+        let mut player_ids: Vec<u64> = self.scorecards.iter()
+            .filter(|card| card.player_name.to_lowercase() == player_name)
+            .map(|card| card.player_id)
+            .collect();
+        player_ids.sort();
+        player_ids.dedup();
+        return Ok(player_ids);
+    }
+}
+
+/// Everything a MemorySource has read, with when it was read. Sources on different threads can share one
+#[derive(Default)]
+pub struct MemoryCache {
+    scorecards: HashMap<u64, (Instant, Vec<Scorecard>)>,
+    history: Option<(Instant, Vec<Scorecard>)>,
+    population: Option<(Instant, PopulationStats)>,
+    player_ids: HashMap<String, (Instant, Vec<u64>)>
+}
+
+/// Keeps everything read from another data source in memory, so a batch of selections reads each player only once.
+/// With a max age, anything older is read again so a long running server sees new games
+pub struct MemorySource {
+    inner: Box<dyn DataSource>,
+    cache: Arc<Mutex<MemoryCache>>,
+    max_age: Option<Duration>
+}
+
+impl MemorySource {
+    pub fn new(inner : Box<dyn DataSource>) -> MemorySource {
+        return MemorySource { inner, cache: Arc::new(Mutex::new(MemoryCache::default())), max_age: None };
+    }
+
+    pub fn shared(inner : Box<dyn DataSource>, cache : Arc<Mutex<MemoryCache>>, max_age : Duration) -> MemorySource {
+        return MemorySource { inner, cache, max_age: Some(max_age) };
+    }
+
+    fn is_fresh(&self, read_at : &Instant) -> bool {
+        return self.max_age.is_none_or(|max_age| read_at.elapsed() < max_age);
+    }
+
+    /// The lock is only held while reading or updating the cache, never while waiting on the inner source
+    fn cache(&self) -> MutexGuard<'_, MemoryCache> {
+        // A thread that panicked mid update can only have left a stale entry behind, which gets read again anyway
+        return self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+impl DataSource for MemorySource {
+    fn player_scorecards(&mut self, player_ids : &[u64]) -> Result<Vec<Scorecard>, SelectorError> {
+        let mut missing: Vec<u64> = {
+            let cache = self.cache();
+            player_ids.iter().filter(|player_id| !cache.scorecards.get(player_id).is_some_and(|(read_at, _)| self.is_fresh(read_at))).copied().collect()
+        };
+        missing.sort();
+        missing.dedup();
+
+        if !missing.is_empty() {
+            let fetched = self.inner.player_scorecards(&missing)?;
+            let now = Instant::now();
+            let mut cache = self.cache();
+            // Players without any games are remembered too, so they aren't looked up again
+            for player_id in &missing {
+                cache.scorecards.insert(*player_id, (now, Vec::new()));
+            }
+            for card in fetched {
+                cache.scorecards.entry(card.player_id).or_insert_with(|| (now, Vec::new())).1.push(card);
+            }
+        }
+
+        let cache = self.cache();
+        let mut scorecards: Vec<Scorecard> = player_ids.iter()
+            .filter_map(|player_id| cache.scorecards.get(player_id))
+            .flat_map(|(_, cards)| cards.iter().cloned())
+            .collect();
+        scorecards.sort_by(|a, b| b.played_at.cmp(&a.played_at).then(b.game_id.cmp(&a.game_id)));
+        return Ok(scorecards);
+    }
+
+    fn history(&mut self) -> Result<Vec<Scorecard>, SelectorError> {
+        if let Some((read_at, history)) = &self.cache().history && self.is_fresh(read_at) {
+            return Ok(history.clone());
+        }
+        let history = self.inner.history()?;
+        self.cache().history = Some((Instant::now(), history.clone()));
+        return Ok(history);
+    }
+
+    fn population_stats(&mut self) -> Result<PopulationStats, SelectorError> {
+        if let Some((read_at, population)) = &self.cache().population && self.is_fresh(read_at) {
+            return Ok(*population);
+        }
+        let population = self.inner.population_stats()?;
+        self.cache().population = Some((Instant::now(), population));
+        return Ok(population);
+    }
+
+    fn player_ids_by_name(&mut self, player_name : &str) -> Result<Vec<u64>, SelectorError> {
+        let key = player_name.to_lowercase();
+        if let Some((read_at, player_ids)) = self.cache().player_ids.get(&key) && self.is_fresh(read_at) {
+            return Ok(player_ids.clone());
+        }
+        let player_ids = self.inner.player_ids_by_name(player_name)?;
+        self.cache().player_ids.insert(key, (Instant::now(), player_ids.clone()));
+        return Ok(player_ids);
+    }
+
+    fn is_available(&mut self) -> Result<bool, SelectorError> {
+        return self.inner.is_available();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_file;
+
+    const JSON_SCORECARDS: &str = r#"[
+        {"mvp_points": 10.5, "hit_diff": 1.5, "position": "Commander", "player_name": "Alice", "player_id": 1, "game_id": 10, "team": "red", "won": true, "played_at": 1000},
+        {"mvp_points": 4.0, "hit_diff": 0.8, "position": "Scout", "player_name": "Bob", "player_id": 2, "game_id": 10, "team": "green", "won": false, "played_at": 1000},
+        {"mvp_points": 8.0, "hit_diff": 1.1, "position": "Medic", "player_name": "Alice", "player_id": 1, "game_id": 12, "team": "green", "won": false, "played_at": 2000},
+        {"mvp_points": 6.0, "hit_diff": 1.2, "position": "Scout", "player_name": "Alice", "player_id": 1, "game_id": 11, "team": "red", "won": true, "played_at": 2000}
+    ]"#;
+
+    #[test]
+    fn reads_json_scorecards() {
+        let path = temp_file("scorecards.json", JSON_SCORECARDS);
+        let mut source = FileSource::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let history = source.history().unwrap();
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[0].player_name, "Alice");
+        assert_eq!(history[1].position, "Scout");
+        assert!(history[0].won && !history[1].won);
+        assert_eq!(source.player_ids_by_name("alice").unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn reads_csv_scorecards_without_played_at() {
+        let path = temp_file("scorecards.csv", "mvp_points,hit_diff,position,player_name,player_id,game_id,team,won\n\
+            10.5,1.5,Commander,Alice,1,10,red,true\n\
+            4.0,0.8,Scout,Bob,2,10,green,false\n");
+        let mut source = FileSource::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let scorecards = source.player_scorecards(&[2]).unwrap();
+        assert_eq!(scorecards.len(), 1);
+        assert_eq!(scorecards[0].mvp_points, 4.0);
+        assert_eq!(scorecards[0].played_at, 0);
+    }
+
+    #[test]
+    fn player_scorecards_are_newest_first() {
+        let path = temp_file("newest-first.json", JSON_SCORECARDS);
+        let mut source = FileSource::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // Games 11 and 12 were played at the same time, so the higher game id counts as newer
+        let game_ids: Vec<i64> = source.player_scorecards(&[1]).unwrap().iter().map(|card| card.game_id).collect();
+        assert_eq!(game_ids, vec![12, 11, 10]);
+    }
+
+    #[test]
+    fn invalid_files_are_config_errors() {
+        let path = temp_file("invalid.json", r#"[{"mvp_points": "lots"}]"#);
+        let result = FileSource::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(SelectorError::Config(_))));
+    }
+
+    #[test]
+    fn history_is_oldest_game_first() {
+        // Sorted by player, the way exports often are, so game 10's scorecards are split up
+        let path = temp_file("by-player.json", r#"[
+            {"mvp_points": 8.0, "hit_diff": 1.1, "position": "Medic", "player_name": "Alice", "player_id": 1, "game_id": 12, "team": "green", "won": false, "played_at": 2000},
+            {"mvp_points": 10.5, "hit_diff": 1.5, "position": "Commander", "player_name": "Alice", "player_id": 1, "game_id": 10, "team": "red", "won": true, "played_at": 1000},
+            {"mvp_points": 6.0, "hit_diff": 1.2, "position": "Scout", "player_name": "Alice", "player_id": 1, "game_id": 11, "team": "red", "won": true, "played_at": 2000},
+            {"mvp_points": 4.0, "hit_diff": 0.8, "position": "Scout", "player_name": "Bob", "player_id": 2, "game_id": 12, "team": "red", "won": true, "played_at": 2000},
+            {"mvp_points": 5.0, "hit_diff": 0.9, "position": "Medic", "player_name": "Bob", "player_id": 2, "game_id": 10, "team": "green", "won": false, "played_at": 1000}
+        ]"#);
+        let mut source = FileSource::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let game_ids: Vec<i64> = source.history().unwrap().iter().map(|card| card.game_id).collect();
+        assert_eq!(game_ids, vec![10, 10, 11, 12, 12]);
+    }
+}
@@ -1,23 +1,76 @@
 use std::{collections::HashMap, hash::{DefaultHasher, Hash, Hasher}};
 
-use crate::{db::{create_db_client, set_database_params}, MVPCalculationMode, PlayerStats, PositionStats};
+use crate::{error::SelectorError, source::DataSource, MVPCalculationMode, NGamesScope, PlayerStats, PopulationStats, PositionStats, Scorecard, StatsParameters};
 
-pub fn retrieve_stats(player_id : u64, mvp_calc_method : MVPCalculationMode) -> PlayerStats {
+/// Load every player's stats with a single scorecards lookup (one query for Postgres). Stats come back in the same order as player_ids
+pub fn retrieve_stats_batch(source : &mut dyn DataSource, player_ids : &[u64], stats_params : &StatsParameters) -> Result<Vec<PlayerStats>, SelectorError> {
 
-    let params = set_database_params();
-    let mut db_client = create_db_client(params);
+    // Nothing to look up when every player is new
+    if player_ids.is_empty() {
+        return Ok(Vec::new());
+    }
 
-    let query = format!("SELECT mvp_points, hit_diff, position, player_name FROM scorecards WHERE player_id = {}", player_id);
-    let results = db_client.query(&query, &[]).expect("Failed to retrieve player stats");
+    // Only the shrinkage mode reads the population, so the others skip looking it up
+    let population = if needs_population(&stats_params.mvp_calc_mode) {
+        source.population_stats()?
+    } else {
+        PopulationTotals::default().averages()
+    };
 
-    let player_name;
+    // Newest games first, so the last n games are always at the front
+    let mut scorecards_by_player: HashMap<u64, Vec<Scorecard>> = HashMap::new();
+    for card in source.player_scorecards(player_ids)? {
+        scorecards_by_player.entry(card.player_id).or_default().push(card);
+    }
 
-    if results.len() == 0 {
-        player_name = String::from("Unknown Player");
-    } else {
-        player_name = results[0].get(3);
+    let unknown: Vec<u64> = player_ids.iter().filter(|player_id| !scorecards_by_player.contains_key(player_id)).copied().collect();
+    if !unknown.is_empty() {
+        return Err(unknown_players(&unknown));
     }
 
+    return Ok(player_ids.iter().map(|player_id| build_player_stats(*player_id, scorecards_by_player[player_id].iter(), stats_params, &population)).collect());
+}
+
+/// Ids given with --player must have played, new players go in with --new-player
+pub fn unknown_players(player_ids : &[u64]) -> SelectorError {
+    return SelectorError::UnknownPlayer(format!("No scorecards were found for player ids {:?}, add new players with --new-player instead.", player_ids));
+}
+
+/// Look up the player id for each name. Every name must belong to exactly one player
+pub fn resolve_player_names(source : &mut dyn DataSource, player_names : &[String]) -> Result<Vec<u64>, SelectorError> {
+    return player_names.iter().map(|player_name| {
+        let player_ids = source.player_ids_by_name(player_name)?;
+        match player_ids.as_slice() {
+            [player_id] => Ok(*player_id),
+            [] => Err(SelectorError::UnknownPlayer(format!("No player named {} was found.", player_name))),
+            _ => Err(SelectorError::UnknownPlayer(format!("More than one player is named {} ({:?}), pass their id with --player instead.", player_name, player_ids)))
+        }
+    }).collect();
+}
+
+/// Work out a player's stats from their scorecards, which must come newest first. Only the shrinkage mode uses `population`
+pub fn build_player_stats<'a, I>(player_id : u64, scorecards : I, stats_params : &StatsParameters, population : &PopulationStats) -> PlayerStats
+where
+    I: Iterator<Item = &'a Scorecard> + Clone,
+{
+
+    let player_name = match scorecards.clone().next() {
+        Some(card) => card.player_name.clone(),
+        None => String::from("Unknown Player")
+    };
+
+    // In overall scope only the most recent n games count, whatever position they were played at
+    let last_n = is_last_n_mode(&stats_params.mvp_calc_mode);
+    let counted_games = if last_n && matches!(stats_params.n_games_scope, NGamesScope::Overall) {
+        stats_params.n_games as usize
+    } else {
+        usize::MAX
+    };
+
+    // Decay is measured back from the player's newest game. Only the weights relative to each other matter, so this gives
+    // the same stats as measuring from today, without the weights all underflowing for players who haven't played in years
+    let newest_game = scorecards.clone().map(|card| card.played_at).max().unwrap_or(0);
+
     let mut stats = HashMap::new();
 
     for position in 0u64..5 {
@@ -26,25 +79,31 @@ pub fn retrieve_stats(player_id : u64, mvp_calc_method : MVPCalculationMode) ->
 
         let mut all_mvps: Vec<f64> = Vec::new();
         let mut all_hit_diffs: Vec<f64> = Vec::new();
-        let mut game_count: u64 = 0;
-
-        for row in &results {
-            let pos: String = row.get(2);
-            if pos == set_position_from_index(position) {
-                all_mvps.push(row.get(0));
-                all_hit_diffs.push(row.get(1));
-                game_count += 1;
+        let mut all_played_at: Vec<i64> = Vec::new();
+
+        // Games played is experience at the position, so it is never cut down to the last n
+        let game_count = scorecards.clone().filter(|card| card.position == position_string).count() as u64;
+
+        for card in scorecards.clone().take(counted_games).filter(|card| card.position == position_string) {
+            if last_n && matches!(stats_params.n_games_scope, NGamesScope::Position) && all_mvps.len() as u64 >= stats_params.n_games {
+                break;
             }
+            all_mvps.push(card.mvp_points);
+            all_hit_diffs.push(card.hit_diff);
+            all_played_at.push(card.played_at);
         }
 
-        let mut mvp_stats = calculate_stats(all_mvps, mvp_calc_method.clone());
+        let (mut mvp_stats, mut hit_diff_stats) = if matches!(stats_params.mvp_calc_mode, MVPCalculationMode::Time_Decay) {
+            let weights = decay_weights(&all_played_at, newest_game, stats_params.half_life_days);
+            (find_weighted_mean(&all_mvps, &weights).unwrap_or(-1.0), find_weighted_mean(&all_hit_diffs, &weights).unwrap_or(-1.0))
+        } else {
+            (calculate_stats(all_mvps, stats_params.mvp_calc_mode.clone()), calculate_stats(all_hit_diffs, stats_params.mvp_calc_mode.clone()))
+        };
 
         if mvp_stats == -1.0 {
             mvp_stats = 7.0;
         }
 
-        let mut hit_diff_stats = calculate_stats(all_hit_diffs, mvp_calc_method.clone());
-        
         if hit_diff_stats == -1.0 {
             hit_diff_stats = 1.0;
         }
@@ -59,14 +118,22 @@ pub fn retrieve_stats(player_id : u64, mvp_calc_method : MVPCalculationMode) ->
         //all_games_played += game_count;
     }
 
+    if matches!(stats_params.mvp_calc_mode, MVPCalculationMode::Shrinkage) {
+        let mut position_stats: Vec<PositionStats> = (0u64..5).map(|position| stats[&set_position_from_index(position)]).collect();
+        shrink_towards_population(&mut position_stats, population, stats_params.shrinkage_strength);
+        for (position, shrunk) in position_stats.into_iter().enumerate() {
+            stats.insert(set_position_from_index(position as u64), shrunk);
+        }
+    }
+
     let final_stats = PlayerStats {
-        player_id: player_id,
-        player_name: player_name,
-        commander_stats: stats.get("Commander").unwrap().clone(),
-        heavy_stats: stats.get("Heavy Weapons").unwrap().clone(),
-        scout_stats: stats.get("Scout").unwrap().clone(),
-        ammo_stats: stats.get("Ammo Carrier").unwrap().clone(),
-        medic_stats: stats.get("Medic").unwrap().clone(),
+        player_id,
+        player_name,
+        commander_stats: *stats.get("Commander").unwrap(),
+        heavy_stats: *stats.get("Heavy Weapons").unwrap(),
+        scout_stats: *stats.get("Scout").unwrap(),
+        ammo_stats: *stats.get("Ammo Carrier").unwrap(),
+        medic_stats: *stats.get("Medic").unwrap(),
         //total_games_played: all_games_played,
     };
 
@@ -99,11 +166,11 @@ pub fn retrieve_stats_new(player_name : String) -> PlayerStats {
     let final_stats = PlayerStats {
         player_id: final_id,
         player_name,
-        commander_stats: stats.get("Commander").unwrap().clone(),
-        heavy_stats: stats.get("Heavy Weapons").unwrap().clone(),
-        scout_stats: stats.get("Scout").unwrap().clone(),
-        ammo_stats: stats.get("Ammo Carrier").unwrap().clone(),
-        medic_stats: stats.get("Medic").unwrap().clone(),
+        commander_stats: *stats.get("Commander").unwrap(),
+        heavy_stats: *stats.get("Heavy Weapons").unwrap(),
+        scout_stats: *stats.get("Scout").unwrap(),
+        ammo_stats: *stats.get("Ammo Carrier").unwrap(),
+        medic_stats: *stats.get("Medic").unwrap(),
         //total_games_played: all_games_played,
     };
 
@@ -122,7 +189,7 @@ where
     sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
 
     let len = sorted.len();
-    if len % 2 == 0 {
+    if len.is_multiple_of(2) {
         let mid_right = len / 2;
         let mid_left = mid_right - 1;
         Some((sorted[mid_left].into() + sorted[mid_right].into()) / 2.0)
@@ -143,6 +210,22 @@ where
     Some(sum / values.len() as f64)
 }
 
+fn find_weighted_mean(values: &[f64], weights: &[f64]) -> Option<f64> {
+    let total_weight: f64 = weights.iter().sum();
+    if values.is_empty() || total_weight <= 0.0 {
+        return None;
+    }
+
+    let sum: f64 = values.iter().zip(weights).map(|(value, weight)| value * weight).sum();
+    Some(sum / total_weight)
+}
+
+/// Each game's weight halves every half_life_days before the newest game
+fn decay_weights(played_at : &[i64], newest_game : i64, half_life_days : f64) -> Vec<f64> {
+    let half_life_seconds = half_life_days * 86400.0;
+    return played_at.iter().map(|time| 0.5f64.powf((newest_game - time) as f64 / half_life_seconds)).collect();
+}
+
 fn calculate_stats(stats : Vec<f64>, mvp_calc_method : MVPCalculationMode) -> f64 {
     match mvp_calc_method {
         MVPCalculationMode::Median => {
@@ -151,15 +234,103 @@ fn calculate_stats(stats : Vec<f64>, mvp_calc_method : MVPCalculationMode) -> f6
         MVPCalculationMode::Mean => {
             return find_mean(&stats).unwrap_or(-1.0);
         }
+        // The last n games were already picked out in retrieve_stats
         MVPCalculationMode::Last_N_Mean => {
-            todo!("N mode support is not done.")
+            return find_mean(&stats).unwrap_or(-1.0);
         }
         MVPCalculationMode::Last_N_Median => {
-            todo!("N mode support is not done.")
+            return find_median(&stats).unwrap_or(-1.0);
+        }
+        // Blended towards the population in build_player_stats
+        MVPCalculationMode::Shrinkage => {
+            return find_mean(&stats).unwrap_or(-1.0);
+        }
+        MVPCalculationMode::Time_Decay => {
+            unreachable!("Time decayed stats need each game's date, they are weighted in build_player_stats.");
+        }
+        MVPCalculationMode::Glicko => {
+            unreachable!("Glicko ratings come from every player's games, use a StatsTracker over the full history.");
         }
     }
 }
 
+/// Blend each position's mean towards what we'd expect of the player there, weighted by games played. The expectation is the
+/// population mean at that position, scaled by how the player does against the population at their other positions.
+/// Either blend gives the expectation `strength` games worth of weight.
+fn shrink_towards_population(position_stats : &mut [PositionStats], population : &PopulationStats, strength : f64) {
+    let observed = position_stats.to_vec();
+
+    let shrink = |position : usize, population_means : &[f64; 5], value : fn(&PositionStats) -> f64| {
+        // How the player compares to everyone else at their other positions, 1.0 is average
+        let mut other_games = 0.0;
+        let mut other_relative = 0.0;
+        for (other, stats) in observed.iter().enumerate() {
+            if other != position && stats.games_played > 0 && population_means[other] > 0.0 {
+                other_games += stats.games_played as f64;
+                other_relative += stats.games_played as f64 * value(stats) / population_means[other];
+            }
+        }
+        let relative = if other_games > 0.0 { (other_relative + strength) / (other_games + strength) } else { 1.0 };
+        let expected = population_means[position] * relative;
+
+        let games = observed[position].games_played as f64;
+        let own = if games > 0.0 { value(&observed[position]) } else { 0.0 };
+        (games * own + strength * expected) / (games + strength)
+    };
+
+    for (position, stats) in position_stats.iter_mut().enumerate() {
+        stats.mvp = shrink(position, &population.mvp, |stats| stats.mvp);
+        stats.hit_diff = shrink(position, &population.hit_diff, |stats| stats.hit_diff);
+    }
+}
+
+/// Running MVP and hit diff totals at each position, across everyone
+#[derive(Default)]
+pub struct PopulationTotals {
+    totals: [(f64, f64, u64); 5]
+}
+
+impl PopulationTotals {
+    pub fn add(&mut self, card : &Scorecard) {
+        if let Some(position) = position_index_from_name(&card.position) {
+            let total = &mut self.totals[position as usize];
+            total.0 += card.mvp_points;
+            total.1 += card.hit_diff;
+            total.2 += 1;
+        }
+    }
+
+    /// Average MVP and hit diff at each position. Positions nobody has played get 7.0 and 1.0
+    pub fn averages(&self) -> PopulationStats {
+        let mut population = PopulationStats { mvp: [7.0; 5], hit_diff: [1.0; 5] };
+        for (position, (mvp_total, hit_diff_total, games)) in self.totals.iter().enumerate() {
+            if *games > 0 {
+                population.mvp[position] = mvp_total / *games as f64;
+                population.hit_diff[position] = hit_diff_total / *games as f64;
+            }
+        }
+        return population;
+    }
+}
+
+/// Modes that blend players' stats towards everyone else's, so need the population stats
+pub fn needs_population(mvp_calc_method : &MVPCalculationMode) -> bool {
+    matches!(mvp_calc_method, MVPCalculationMode::Shrinkage)
+}
+
+/// Modes that rate players against each other, so can't be worked out from one player's scorecards alone
+pub fn needs_full_history(mvp_calc_method : &MVPCalculationMode) -> bool {
+    matches!(mvp_calc_method, MVPCalculationMode::Glicko)
+}
+
+fn is_last_n_mode(mvp_calc_method : &MVPCalculationMode) -> bool {
+    matches!(mvp_calc_method, MVPCalculationMode::Last_N_Median | MVPCalculationMode::Last_N_Mean)
+}
+
+pub fn position_index_from_name(name : &str) -> Option<u64> {
+    (0u64..5).find(|position| set_position_from_index(*position) == name)
+}
+
 pub fn set_position_from_index(i : u64) -> String {
     match i {
         0 => {return "Commander".to_string();}
@@ -167,6 +338,176 @@ pub fn set_position_from_index(i : u64) -> String {
         2 => {return "Scout".to_string();}
         3 => {return "Ammo Carrier".to_string();}
         4 => {return "Medic".to_string();}
-        _ => {panic!("How did we get here??");}
+        _ => {unreachable!("Position index {} does not exist, positions are 0 to 4.", i);}
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::{source::FileSource, test_support::{scorecard, temp_file}};
+
+    const DAY : i64 = 86400;
+
+    /// One of player 1's games, identified by when it was played
+    fn card(position : &str, mvp_points : f64, hit_diff : f64, played_at : i64) -> Scorecard {
+        return Scorecard { hit_diff, ..scorecard(1, position, mvp_points, played_at, "red", true) };
+    }
+
+    fn parameters(mode : MVPCalculationMode) -> StatsParameters {
+        return StatsParameters::new(mode, 50, NGamesScope::default(), 10.0, 1.0).unwrap();
+    }
+
+    fn stats_at(games_played : u64, mvp : f64, hit_diff : f64) -> PositionStats {
+        return PositionStats { mvp, hit_diff, games_played };
+    }
+
+    #[test]
+    fn shrinkage_without_games_gives_the_population_average() {
+        let population = PopulationStats { mvp: [6.0, 7.0, 8.0, 9.0, 10.0], hit_diff: [1.0, 1.5, 2.0, 2.5, 3.0] };
+        let mut stats = [stats_at(0, 0.0, 0.0); 5];
+        shrink_towards_population(&mut stats, &population, 10.0);
+        for (position, shrunk) in stats.iter().enumerate() {
+            assert!((shrunk.mvp - population.mvp[position]).abs() < 1e-9);
+            assert!((shrunk.hit_diff - population.hit_diff[position]).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn shrinkage_blends_by_games_played() {
+        let population = PopulationStats { mvp: [10.0; 5], hit_diff: [1.0; 5] };
+        let mut stats = [stats_at(0, 0.0, 0.0); 5];
+        stats[0] = stats_at(10, 20.0, 2.0);
+        shrink_towards_population(&mut stats, &population, 10.0);
+
+        // 10 games at 20 and 10 games worth of the population's 10
+        assert!((stats[0].mvp - 15.0).abs() < 1e-9);
+        assert!((stats[0].hit_diff - 1.5).abs() < 1e-9);
+        // Twice the population at commander, blended with 10 games of average, makes 1.5 times the population elsewhere
+        assert!((stats[1].mvp - 15.0).abs() < 1e-9);
+        assert!((stats[4].hit_diff - 1.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn shrinkage_barely_moves_players_with_many_games() {
+        let population = PopulationStats { mvp: [10.0; 5], hit_diff: [1.0; 5] };
+        let mut stats = [stats_at(1000, 20.0, 2.0); 5];
+        shrink_towards_population(&mut stats, &population, 10.0);
+        assert!((stats[2].mvp - 20.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn shrinkage_mode_uses_the_population() {
+        let population = PopulationStats { mvp: [10.0; 5], hit_diff: [1.0; 5] };
+        let cards: Vec<Scorecard> = (0..10).map(|game| card("Commander", 20.0, 2.0, game)).collect();
+        let stats = build_player_stats(1, cards.iter(), &parameters(MVPCalculationMode::Shrinkage), &population);
+        assert!((stats.commander_stats.mvp - 15.0).abs() < 1e-9);
+        assert!((stats.scout_stats.mvp - 15.0).abs() < 1e-9);
+        assert_eq!(stats.commander_stats.games_played, 10);
+    }
+
+    #[test]
+    fn shrinkage_strength_must_be_positive() {
+        for shrinkage_strength in [0.0, -10.0, f64::NAN] {
+            assert!(matches!(StatsParameters::new(MVPCalculationMode::Shrinkage, 50, NGamesScope::default(), shrinkage_strength, 365.0), Err(SelectorError::Config(_))));
+        }
+    }
+
+    fn last_n(mode : MVPCalculationMode, n_games : u64, n_games_scope : NGamesScope) -> StatsParameters {
+        return StatsParameters::new(mode, n_games, n_games_scope, 10.0, 365.0).unwrap();
+    }
+
+    #[test]
+    fn last_n_modes_only_use_the_first_n_games() {
+        // Newest first
+        let cards: Vec<Scorecard> = [10.0, 20.0, 30.0, 40.0, 50.0].iter().enumerate()
+            .map(|(age, mvp_points)| card("Commander", *mvp_points, 1.0, -(age as i64) * DAY))
+            .collect();
+
+        let mean = build_player_stats(1, cards.iter(), &last_n(MVPCalculationMode::Last_N_Mean, 2, NGamesScope::Position), &PopulationTotals::default().averages());
+        assert_eq!(mean.commander_stats.mvp, 15.0);
+        let median = build_player_stats(1, cards.iter(), &last_n(MVPCalculationMode::Last_N_Median, 3, NGamesScope::Position), &PopulationTotals::default().averages());
+        assert_eq!(median.commander_stats.mvp, 20.0);
+        // Every game still counts as experience
+        assert_eq!(mean.commander_stats.games_played, 5);
+
+        let all = build_player_stats(1, cards.iter(), &last_n(MVPCalculationMode::Last_N_Mean, 50, NGamesScope::Position), &PopulationTotals::default().averages());
+        assert_eq!(all.commander_stats.mvp, 30.0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn last_n_uses_the_newest_games_from_the_source() {
+        let cards = [card("Commander", 10.0, 1.0, 3 * DAY), card("Commander", 40.0, 1.0, 0), card("Commander", 20.0, 1.0, 2 * DAY), card("Commander", 30.0, 1.0, DAY)];
+        let path = temp_file("stats-newest-first.json", &serde_json::to_string(&cards).unwrap());
+        let mut source = FileSource::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let stats = retrieve_stats_batch(&mut source, &[1], &last_n(MVPCalculationMode::Last_N_Mean, 2, NGamesScope::Position)).unwrap();
+        assert_eq!(stats[0].commander_stats.mvp, 15.0);
+    }
+
+    #[test]
+    fn scope_picks_the_last_n_games_at_each_position_or_overall() {
+        // Newest first
+        let cards = [
+            card("Commander", 10.0, 1.0, 4 * DAY),
+            card("Heavy Weapons", 100.0, 1.0, 3 * DAY),
+            card("Heavy Weapons", 200.0, 1.0, 2 * DAY),
+            card("Commander", 20.0, 1.0, DAY),
+            card("Commander", 30.0, 1.0, 0)
+        ];
+
+        let position = build_player_stats(1, cards.iter(), &last_n(MVPCalculationMode::Last_N_Mean, 2, NGamesScope::Position), &PopulationTotals::default().averages());
+        assert_eq!(position.commander_stats.mvp, 15.0);
+        assert_eq!(position.heavy_stats.mvp, 150.0);
+
+        // Only the two newest games, one at each position
+        let overall = build_player_stats(1, cards.iter(), &last_n(MVPCalculationMode::Last_N_Mean, 2, NGamesScope::Overall), &PopulationTotals::default().averages());
+        assert_eq!(overall.commander_stats.mvp, 10.0);
+        assert_eq!(overall.heavy_stats.mvp, 100.0);
+        assert_eq!(overall.commander_stats.games_played, 3);
+        assert_eq!(overall.heavy_stats.games_played, 2);
+    }
+
+    #[test]
+    fn n_games_must_be_positive() {
+        assert!(matches!(StatsParameters::new(MVPCalculationMode::Last_N_Mean, 0, NGamesScope::default(), 10.0, 365.0), Err(SelectorError::Config(_))));
+    }
+
+    #[test]
+    fn decay_weights_halve_every_half_life() {
+        let weights = decay_weights(&[10 * DAY, 7 * DAY, 4 * DAY], 10 * DAY, 3.0);
+        assert_eq!(weights, vec![1.0, 0.5, 0.25]);
+    }
+
+    #[test]
+    fn time_decay_weights_newer_games_more() {
+        // Newest first, with a half life of one day
+        let cards = [card("Commander", 10.0, 1.0, DAY), card("Commander", 40.0, 4.0, 0)];
+        let stats = build_player_stats(1, cards.iter(), &parameters(MVPCalculationMode::Time_Decay), &PopulationTotals::default().averages());
+        assert!((stats.commander_stats.mvp - 20.0).abs() < 1e-9);
+        assert!((stats.commander_stats.hit_diff - 2.0).abs() < 1e-9);
+        assert_eq!(stats.commander_stats.games_played, 2);
+        // Positions without games get the usual defaults
+        assert_eq!(stats.medic_stats.mvp, 7.0);
+        assert_eq!(stats.medic_stats.hit_diff, 1.0);
+    }
+
+    #[test]
+    fn time_decay_only_depends_on_how_far_apart_games_are() {
+        let recent = [card("Heavy Weapons", 5.0, 1.0, 100 * DAY), card("Commander", 10.0, 1.0, 99 * DAY), card("Commander", 40.0, 4.0, 98 * DAY)];
+        let long_ago = [card("Heavy Weapons", 5.0, 1.0, 2 * DAY), card("Commander", 10.0, 1.0, DAY), card("Commander", 40.0, 4.0, 0)];
+        let recent_stats = build_player_stats(1, recent.iter(), &parameters(MVPCalculationMode::Time_Decay), &PopulationTotals::default().averages());
+        let long_ago_stats = build_player_stats(1, long_ago.iter(), &parameters(MVPCalculationMode::Time_Decay), &PopulationTotals::default().averages());
+        assert!((recent_stats.commander_stats.mvp - 20.0).abs() < 1e-9);
+        assert!((long_ago_stats.commander_stats.mvp - 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn half_life_must_be_positive() {
+        for half_life_days in [0.0, -1.0] {
+            assert!(matches!(StatsParameters::new(MVPCalculationMode::Time_Decay, 50, NGamesScope::default(), 10.0, half_life_days), Err(SelectorError::Config(_))));
+        }
+    }
+}
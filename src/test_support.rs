@@ -0,0 +1,63 @@
+//! Players, scorecards and files shared by the tests in every module
+
+use std::{fs, path::PathBuf};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{PlayerStats, PositionStats, Scorecard};
+
+/// Players 1 to count with random stats at every position, the same ones for the same seed
+pub fn random_players(count : u64, seed : u64) -> Vec<PlayerStats> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut random_stats = || PositionStats { mvp: rng.random_range(1.0..20.0), hit_diff: rng.random_range(0.5..2.0), games_played: rng.random_range(0..50) };
+    return (1..=count).map(|player_id| PlayerStats {
+        player_id,
+        player_name: format!("p{}", player_id),
+        commander_stats: random_stats(),
+        heavy_stats: random_stats(),
+        scout_stats: random_stats(),
+        ammo_stats: random_stats(),
+        medic_stats: random_stats()
+    }).collect();
+}
+
+/// Players 1 to count who are equally good at every position, the higher the id the better
+pub fn ranked_players(count : u64) -> Vec<PlayerStats> {
+    let stats = |player_id : u64| PositionStats { mvp: player_id as f64, hit_diff: 1.0, games_played: 0 };
+    return (1..=count).map(|player_id| PlayerStats {
+        player_id,
+        player_name: format!("p{}", player_id),
+        commander_stats: stats(player_id),
+        heavy_stats: stats(player_id),
+        scout_stats: stats(player_id),
+        ammo_stats: stats(player_id),
+        medic_stats: stats(player_id)
+    }).collect();
+}
+
+/// A scorecard for player `p<id>` with a hit diff of 1, played at `game_id` seconds. Change the rest with struct update syntax
+pub fn scorecard(player_id : u64, position : &str, mvp_points : f64, game_id : i64, team : &str, won : bool) -> Scorecard {
+    return Scorecard {
+        mvp_points,
+        hit_diff: 1.0,
+        position: position.to_string(),
+        player_name: format!("p{}", player_id),
+        player_id,
+        game_id,
+        team: team.to_string(),
+        won,
+        played_at: game_id
+    };
+}
+
+/// A path in the temp directory that only this test run uses
+pub fn temp_path(name : &str) -> PathBuf {
+    return std::env::temp_dir().join(format!("selector-backend-{}-{}", std::process::id(), name));
+}
+
+/// Write a file at temp_path
+pub fn temp_file(name : &str, contents : &str) -> PathBuf {
+    let path = temp_path(name);
+    fs::write(&path, contents).unwrap();
+    return path;
+}
@@ -0,0 +1,179 @@
+use crate::{error::SelectorError, history::{split_teams, HistoricalGame, StatsTracker, TeamFeatures}, prediction::PredictionModel, stats::set_position_from_index, StatsParameters};
+
+/// Fewer games than this won't give weights worth trusting
+const MINIMUM_TRAINING_GAMES: usize = 100;
+
+/// Keeps the fit stable when a position barely varies between teams
+const RIDGE: f64 = 1e-3;
+
+/// What training found
+pub struct TrainingReport {
+    pub model: PredictionModel,
+    /// How many games the model was fit to
+    pub games: usize,
+    /// How many of those games the model picked the winner of
+    pub correct: usize
+}
+
+/// Fit the prediction model to every two team game in history, ready to save with save_model.
+/// Each game is described by the teams' stats going into it, so the weights only learn from what the selector could have known then.
+pub fn train_model(history : &[HistoricalGame], stats_params : &StatsParameters) -> Result<TrainingReport, SelectorError> {
+
+    let mut tracker = StatsTracker::default();
+
+    let mut features: Vec<TeamFeatures> = Vec::new();
+    let mut labels: Vec<bool> = Vec::new();
+
+    for game in history {
+        // Draws and games with missing results say nothing about who should win
+        if let Some([first, second]) = split_teams(game) && first.won != second.won {
+            let first_features = tracker.team_features(&first, stats_params);
+            let second_features = tracker.team_features(&second, stats_params);
+            features.push(std::array::from_fn(|i| first_features[i] - second_features[i]));
+            labels.push(first.won);
+        }
+        tracker.record(game);
+    }
+
+    if features.len() < MINIMUM_TRAINING_GAMES {
+        return Err(SelectorError::Training(format!("Only {} usable games were found, at least {} are needed to train a model.", features.len(), MINIMUM_TRAINING_GAMES)));
+    }
+
+    let coefficients = fit_logistic(&features, &labels);
+
+    // Split the coefficients into a scale and weights that average 1, so smvp stays on the scale people are used to
+    let scale = coefficients[..5].iter().sum::<f64>() / 5.0;
+    if scale <= 0.0 {
+        return Err(SelectorError::Training(String::from("Training found no link between player stats and winning, the model was not saved.")));
+    }
+
+    let model = PredictionModel {
+        position_weights: std::array::from_fn(|i| coefficients[i] / scale),
+        games_weight: coefficients[5] / scale,
+        scale
+    };
+
+    // A negative weight would rank players lower the better they do at that position, and the selection would pick teams backwards
+    let backwards: Vec<String> = (0..5).filter(|i| model.position_weights[*i] < 0.0).map(|i| set_position_from_index(i as u64)).collect();
+    if !backwards.is_empty() {
+        return Err(SelectorError::Training(format!("Training found that doing better as {} makes a team less likely to win, the model was not saved. Try more games or another --mvp-calculation-mode.", backwards.join(", "))));
+    }
+
+    let correct = features.iter().zip(&labels).filter(|(x, won)| (dot(&coefficients, x) > 0.0) == **won).count();
+
+    return Ok(TrainingReport { model, games: features.len(), correct });
+}
+
+/// Logistic regression without an intercept (which team is listed first means nothing), fit with Newton's method
+fn fit_logistic(features : &[TeamFeatures], labels : &[bool]) -> TeamFeatures {
+    let mut weights = [0.0; 6];
+
+    for _iteration in 0..50 {
+        let mut gradient = [0.0; 6];
+        let mut hessian = [[0.0; 6]; 6];
+
+        for (x, won) in features.iter().zip(labels) {
+            let p = 1.0 / (1.0 + (-dot(&weights, x)).exp());
+            let y = if *won { 1.0 } else { 0.0 };
+            for i in 0..6 {
+                gradient[i] += (y - p) * x[i];
+                for j in 0..6 {
+                    hessian[i][j] += p * (1.0 - p) * x[i] * x[j];
+                }
+            }
+        }
+
+        for i in 0..6 {
+            gradient[i] -= RIDGE * weights[i];
+            hessian[i][i] += RIDGE;
+        }
+
+        let step = solve(hessian, gradient);
+        for i in 0..6 {
+            weights[i] += step[i];
+        }
+
+        if step.iter().all(|change| change.abs() < 1e-9) {
+            break;
+        }
+    }
+
+    weights
+}
+
+/// Gaussian elimination with partial pivoting
+fn solve(mut a : [[f64; 6]; 6], mut b : [f64; 6]) -> [f64; 6] {
+    for column in 0..6 {
+        let pivot = (column..6).max_by(|x, y| a[*x][column].abs().total_cmp(&a[*y][column].abs())).unwrap();
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+
+        let pivot_row = a[column];
+        for row in column + 1..6 {
+            let factor = a[row][column] / pivot_row[column];
+            for (k, value) in a[row].iter_mut().enumerate().skip(column) {
+                *value -= factor * pivot_row[k];
+            }
+            b[row] -= factor * b[column];
+        }
+    }
+
+    let mut x = [0.0; 6];
+    for row in (0..6).rev() {
+        let sum: f64 = (row + 1..6).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    x
+}
+
+fn dot(a : &TeamFeatures, b : &TeamFeatures) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{test_support::scorecard, MVPCalculationMode, NGamesScope};
+
+    /// Two player teams, a commander and a heavy each. Every player always scores the same MVP, and a team's chance of
+    /// winning goes with heavy_effect times its heavy's MVP plus commander_effect times its commander's
+    fn history(commander_effect : f64, heavy_effect : f64) -> Vec<HistoricalGame> {
+        let mut rng = StdRng::seed_from_u64(0);
+        return (0..600).map(|game_id| {
+            let commanders = [rng.random_range(1..=10u64), rng.random_range(1..=10u64)];
+            let heavies = [rng.random_range(11..=20u64), rng.random_range(11..=20u64)];
+            let mut strength = |team : usize| commander_effect * commanders[team] as f64 + heavy_effect * (heavies[team] - 10) as f64 + rng.random_range(-3.0..3.0);
+            let red_won = strength(0) > strength(1);
+
+            // Heavies are players 11 to 20, but score 1 to 10 like the commanders
+            let card = |player_id : u64, position : &str, team : usize| {
+                let mvp_points = (if player_id > 10 { player_id - 10 } else { player_id }) as f64;
+                scorecard(player_id, position, mvp_points, game_id, if team == 0 { "red" } else { "green" }, (team == 0) == red_won)
+            };
+            let scorecards = (0..2).flat_map(|team| [card(commanders[team], "Commander", team), card(heavies[team], "Heavy Weapons", team)]).collect();
+            HistoricalGame { game_id, scorecards }
+        }).collect();
+    }
+
+    fn parameters() -> StatsParameters {
+        return StatsParameters::new(MVPCalculationMode::Mean, 50, NGamesScope::default(), 10.0, 365.0).unwrap();
+    }
+
+    #[test]
+    fn trains_positive_weights() {
+        let report = train_model(&history(1.0, 1.0), &parameters()).unwrap();
+        assert_eq!(report.games, 600);
+        assert!(report.model.scale > 0.0);
+        assert!(report.model.position_weights[0] > 0.0 && report.model.position_weights[1] > 0.0);
+    }
+
+    #[test]
+    fn refuses_negative_position_weights() {
+        match train_model(&history(-1.0, 2.0), &parameters()) {
+            Err(SelectorError::Training(message)) => assert!(message.contains("Commander") && !message.contains("Heavy Weapons"), "{}", message),
+            _ => panic!("a negative commander weight should not train")
+        }
+    }
+}